@@ -0,0 +1,318 @@
+//! Application Default Credentials: find credentials the way Google's client libraries do.
+//!
+//! The following locations are tried, in this order:
+//! 1. The credentials file named by the `GOOGLE_APPLICATION_CREDENTIALS` environment variable.
+//! 2. The well-known file written by `gcloud auth application-default login`, i.e.
+//!    `~/.config/gcloud/application_default_credentials.json` (`%APPDATA%\gcloud\...` on
+//!    Windows, or below `$CLOUDSDK_CONFIG` if set).
+//! 3. The GCE metadata server, if the program runs on Google Compute Engine or a similar
+//!    environment.
+//!
+//! See [Google's documentation](https://cloud.google.com/docs/authentication/production) for
+//! details.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::authorized_user::{AuthorizedUserAccess, AuthorizedUserSecret};
+use crate::external_account::{ExternalAccountAccess, ExternalAccountSecret};
+use crate::metadata::{metadata_host_from_env, metadata_url, MetadataAccess, METADATA_IP_URL};
+use crate::service_account::{ServiceAccountAccess, ServiceAccountKey};
+use crate::types::{BoxedTokenSource, RequestError};
+
+use futures::{future, prelude::*};
+
+/// Environment variable naming a credentials file.
+const CREDENTIALS_ENV_VAR: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+/// Environment variable overriding the gcloud configuration directory.
+const CLOUDSDK_CONFIG_ENV_VAR: &str = "CLOUDSDK_CONFIG";
+/// File name of the credentials written by gcloud.
+const WELL_KNOWN_FILE: &str = "application_default_credentials.json";
+
+/// The supported kinds of credential files, distinguished by their `type` field.
+#[derive(Debug)]
+enum CredentialsFile {
+    ServiceAccount(ServiceAccountKey),
+    AuthorizedUser(AuthorizedUserSecret),
+    ExternalAccount(ExternalAccountSecret),
+}
+
+fn parse_credentials_file(contents: &str) -> io::Result<CredentialsFile> {
+    #[derive(Deserialize)]
+    struct CredentialsType {
+        #[serde(rename = "type")]
+        cred_type: String,
+    }
+
+    let invalid = |e: serde_json::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad credentials file: {}", e),
+        )
+    };
+    let cred_type: CredentialsType = serde_json::from_str(contents).map_err(invalid)?;
+    match cred_type.cred_type.as_str() {
+        "service_account" => serde_json::from_str(contents)
+            .map(CredentialsFile::ServiceAccount)
+            .map_err(invalid),
+        "authorized_user" => serde_json::from_str(contents)
+            .map(CredentialsFile::AuthorizedUser)
+            .map_err(invalid),
+        "external_account" => serde_json::from_str(contents)
+            .map(CredentialsFile::ExternalAccount)
+            .map_err(invalid),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported credentials type '{}'", other),
+        )),
+    }
+}
+
+/// Location of the credentials file written by `gcloud auth application-default login`.
+fn well_known_file() -> Option<PathBuf> {
+    let config_dir = if let Some(dir) = env::var_os(CLOUDSDK_CONFIG_ENV_VAR) {
+        PathBuf::from(dir)
+    } else if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?).join("gcloud")
+    } else {
+        PathBuf::from(env::var_os("HOME")?)
+            .join(".config")
+            .join("gcloud")
+    };
+    Some(config_dir.join(WELL_KNOWN_FILE))
+}
+
+/// Look for a credentials file at `env_path`, the value of `GOOGLE_APPLICATION_CREDENTIALS`, and
+/// the well-known location. Returns `Ok(None)` if there is none.
+fn find_credentials_file(env_path: Option<OsString>) -> io::Result<Option<CredentialsFile>> {
+    if let Some(path) = env_path {
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Couldn't read {} ({}): {}",
+                    CREDENTIALS_ENV_VAR,
+                    PathBuf::from(path).display(),
+                    e
+                ),
+            )
+        })?;
+        return parse_credentials_file(&contents).map(Some);
+    }
+    match well_known_file().map(fs::read_to_string) {
+        Some(Ok(contents)) => parse_credentials_file(&contents).map(Some),
+        Some(Err(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Some(Err(e)) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Finds credentials the way Google's client libraries do (see the module documentation), and
+/// yields the matching token source.
+///
+/// ```no_run
+/// use futures::prelude::*;
+/// use yup_oauth2::{ApplicationDefaultCredentials, GetToken};
+///
+/// let fut = ApplicationDefaultCredentials::new()
+///     .build()
+///     .and_then(|mut source| source.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+///     .map(|token| println!("The token is {:?}", token))
+///     .map_err(|e| println!("error: {:?}", e));
+/// tokio::run(fut);
+/// ```
+pub struct ApplicationDefaultCredentials<C> {
+    client: C,
+    probe_timeout: Duration,
+}
+
+impl ApplicationDefaultCredentials<DefaultHyperClient> {
+    /// Create a new ApplicationDefaultCredentials lookup using the default hyper client.
+    pub fn new() -> Self {
+        ApplicationDefaultCredentials {
+            client: DefaultHyperClient,
+            probe_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Default for ApplicationDefaultCredentials<DefaultHyperClient> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create the token source for a credentials file. The quota project named in the file is kept
+/// on the returned source, as the concrete token source is not accessible anymore.
+fn token_source<C>(credentials: CredentialsFile, client: hyper::Client<C>) -> BoxedTokenSource
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    match credentials {
+        CredentialsFile::ServiceAccount(key) => {
+            BoxedTokenSource::new(ServiceAccountAccess::new(key).hyper_client(client).build())
+        }
+        CredentialsFile::AuthorizedUser(secret) => {
+            let access = AuthorizedUserAccess::new(secret).hyper_client(client);
+            let quota_project_id = access.quota_project_id().map(str::to_string);
+            BoxedTokenSource::new(access.build()).with_quota_project_id(quota_project_id)
+        }
+        CredentialsFile::ExternalAccount(secret) => {
+            let access = ExternalAccountAccess::new(secret).hyper_client(client);
+            let quota_project_id = access.quota_project_id().map(str::to_string);
+            BoxedTokenSource::new(access.build()).with_quota_project_id(quota_project_id)
+        }
+    }
+}
+
+impl<C> ApplicationDefaultCredentials<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static + Clone + Send,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ApplicationDefaultCredentials<NewC> {
+        ApplicationDefaultCredentials {
+            client: hyper_client,
+            probe_timeout: self.probe_timeout,
+        }
+    }
+
+    /// How long to wait for the metadata server before concluding that there is none. The
+    /// default is one second.
+    pub fn probe_timeout(self, timeout: Duration) -> Self {
+        ApplicationDefaultCredentials {
+            probe_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Look up the credentials. Fails if no credentials can be found, or a credentials file is
+    /// malformed.
+    pub fn build(self) -> Box<dyn Future<Item = BoxedTokenSource, Error = RequestError> + Send> {
+        let client = self.client.build_hyper_client();
+        match find_credentials_file(env::var_os(CREDENTIALS_ENV_VAR)) {
+            Err(e) => Box::new(future::err(RequestError::LowLevelError(e))),
+            Ok(Some(credentials)) => Box::new(future::ok(token_source(credentials, client))),
+            Ok(None) => {
+                // Unless `GCE_METADATA_HOST` names another server, the metadata server is
+                // contacted by IP to avoid slow DNS lookups outside of GCE.
+                let probe_url = format!(
+                    "{}/computeMetadata/v1/",
+                    metadata_url(metadata_host_from_env(), METADATA_IP_URL)
+                );
+                let request = hyper::Request::get(probe_url)
+                    .header("Metadata-Flavor", "Google")
+                    .body(hyper::Body::empty())
+                    .unwrap();
+                let probe = tokio_timer::Timeout::new(client.request(request), self.probe_timeout)
+                    .then(|r| match r {
+                        Ok(ref resp)
+                            if resp
+                                .headers()
+                                .get("Metadata-Flavor")
+                                .map_or(false, |v| v == "Google") =>
+                        {
+                            Ok(BoxedTokenSource::new(
+                                MetadataAccess::new().hyper_client(client).build(),
+                            ))
+                        }
+                        _ => Err(RequestError::UserError(format!(
+                            "Could not find application default credentials: set {} or run \
+                             `gcloud auth application-default login`",
+                            CREDENTIALS_ENV_VAR
+                        ))),
+                    });
+                Box::new(probe)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials_file() {
+        let service_account = fs::read_to_string("examples/Sanguine-69411a0c0eea.json").unwrap();
+        match parse_credentials_file(&service_account).unwrap() {
+            CredentialsFile::ServiceAccount(key) => assert_eq!(
+                key.client_email.unwrap(),
+                "oauth2-public-test@sanguine-rhythm-105020.iam.gserviceaccount.com"
+            ),
+            other => panic!("unexpected credentials {:?}", other),
+        }
+
+        let authorized_user = r#"{
+  "client_id": "764086051850-6qr4p6gpi6hn506pt8ejuq83di341hur.apps.googleusercontent.com",
+  "client_secret": "d-FL95Q19q7MQmFpd7hHD0Ty",
+  "refresh_token": "1//0refreshtoken",
+  "type": "authorized_user"
+}"#;
+        match parse_credentials_file(authorized_user).unwrap() {
+            CredentialsFile::AuthorizedUser(secret) => {
+                assert_eq!(secret.refresh_token, "1//0refreshtoken")
+            }
+            other => panic!("unexpected credentials {:?}", other),
+        }
+
+        let external_account = r#"{
+  "type": "external_account",
+  "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+  "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+  "token_url": "https://sts.googleapis.com/v1/token",
+  "credential_source": {"file": "/var/run/secrets/token"}
+}"#;
+        match parse_credentials_file(external_account).unwrap() {
+            CredentialsFile::ExternalAccount(secret) => assert_eq!(
+                secret.credential_source.file.unwrap(),
+                "/var/run/secrets/token"
+            ),
+            other => panic!("unexpected credentials {:?}", other),
+        }
+
+        let unknown = r#"{"type": "something_else"}"#;
+        assert!(parse_credentials_file(unknown).is_err());
+    }
+
+    #[test]
+    fn test_token_source_quota_project() {
+        let authorized_user = r#"{
+  "client_id": "764086051850-6qr4p6gpi6hn506pt8ejuq83di341hur.apps.googleusercontent.com",
+  "client_secret": "d-FL95Q19q7MQmFpd7hHD0Ty",
+  "refresh_token": "1//0refreshtoken",
+  "quota_project_id": "myproject",
+  "type": "authorized_user"
+}"#;
+        let service_account = fs::read_to_string("examples/Sanguine-69411a0c0eea.json").unwrap();
+        let source =
+            |json: &str| token_source(parse_credentials_file(json).unwrap(), hyper::Client::new());
+
+        assert_eq!(
+            source(authorized_user).quota_project_id(),
+            Some("myproject")
+        );
+        assert_eq!(source(&service_account).quota_project_id(), None);
+    }
+
+    #[test]
+    fn test_find_credentials_file() {
+        let found = find_credentials_file(Some("examples/Sanguine-69411a0c0eea.json".into()));
+        let missing = find_credentials_file(Some("examples/does-not-exist.json".into()));
+
+        match found.unwrap() {
+            Some(CredentialsFile::ServiceAccount(_)) => {}
+            other => panic!("unexpected credentials {:?}", other),
+        }
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,15 +1,24 @@
 use crate::authenticator_delegate::{AuthenticatorDelegate, DefaultAuthenticatorDelegate, Retry};
 use crate::refresh::RefreshFlow;
-use crate::storage::{hash_scopes, DiskTokenStorage, MemoryStorage, TokenStorage};
-use crate::types::{ApplicationSecret, GetToken, RefreshResult, RequestError, Token};
+use crate::revoke::{RevokeFlow, TokenTypeHint, GOOGLE_REVOKE_URL};
+use crate::storage::{hash_scopes, AsyncTokenStorage, DiskTokenStorage, MemoryStorage};
+use crate::types::{
+    ApplicationSecret, GetToken, PollError, RefreshResult, RequestError, StringError, Token,
+};
 
+use chrono::Utc;
+use futures::sync::oneshot;
 use futures::{future, prelude::*};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::executor::{DefaultExecutor, Executor};
 use tokio_timer;
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 /// Authenticator abstracts different `GetToken` implementations behind one type and handles
 /// caching received tokens. It's important to use it (instead of the flows directly) because
@@ -23,7 +32,7 @@ use std::sync::{Arc, Mutex};
 /// Due to token requests being rare, this should not result in a too bad performance problem.
 struct AuthenticatorImpl<
     T: GetToken,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: hyper::client::connect::Connect,
 > {
@@ -31,6 +40,126 @@ struct AuthenticatorImpl<
     inner: Arc<Mutex<T>>,
     store: Arc<Mutex<S>>,
     delegate: AD,
+    revoke_url: String,
+    in_flight: Arc<Mutex<HashMap<u64, Waiters>>>,
+    /// The scope sets tokens were requested for, so that `revoke()` can find the tokens of a
+    /// revoked grant.
+    scope_sets: Arc<Mutex<HashMap<u64, Vec<String>>>>,
+    background_refresh: Option<BackgroundRefresh>,
+}
+
+impl<T, S, AD, C> Clone for AuthenticatorImpl<T, S, AD, C>
+where
+    T: GetToken,
+    S: AsyncTokenStorage,
+    AD: AuthenticatorDelegate,
+    C: hyper::client::connect::Connect,
+{
+    fn clone(&self) -> Self {
+        AuthenticatorImpl {
+            client: self.client.clone(),
+            inner: self.inner.clone(),
+            store: self.store.clone(),
+            delegate: self.delegate.clone(),
+            revoke_url: self.revoke_url.clone(),
+            in_flight: self.in_flight.clone(),
+            scope_sets: self.scope_sets.clone(),
+            background_refresh: self.background_refresh.clone(),
+        }
+    }
+}
+
+/// Callers waiting for a token that is being obtained by another call to `token()`.
+type Waiters = Vec<oneshot::Sender<Result<Token, RequestError>>>;
+
+/// Marks a token request in progress for a set of scopes; concurrent requests for the same
+/// scopes wait for its result instead of contacting the server themselves. If the request is
+/// dropped before completing, the waiters are woken up and start over.
+struct InFlight {
+    requests: Arc<Mutex<HashMap<u64, Waiters>>>,
+    scope_key: u64,
+    done: bool,
+}
+
+impl InFlight {
+    /// Hands the result to all waiters.
+    fn complete(mut self, result: Result<Token, RequestError>) -> Result<Token, RequestError> {
+        self.done = true;
+        let waiters = self
+            .requests
+            .lock()
+            .unwrap()
+            .remove(&self.scope_key)
+            .unwrap_or_default();
+        match result {
+            Ok(t) => {
+                for w in waiters {
+                    let _ = w.send(Ok(t.clone()));
+                }
+                Ok(t)
+            }
+            Err(e) => {
+                for w in waiters {
+                    let _ = w.send(Err(duplicate_error(&e)));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Builds an error equivalent to `e` for a caller waiting on a concurrent request. Errors
+/// wrapping a `hyper::Error`, which can't be duplicated, are passed on as `LowLevelError`s with
+/// the same message.
+pub(crate) fn duplicate_error(e: &RequestError) -> RequestError {
+    let connection_error = |e: &hyper::Error| {
+        RequestError::LowLevelError(io::Error::new(io::ErrorKind::Other, e.to_string()))
+    };
+    match *e {
+        RequestError::ClientError(ref e) => connection_error(e),
+        RequestError::InvalidClient => RequestError::InvalidClient,
+        RequestError::InvalidScope(ref s) => RequestError::InvalidScope(s.clone()),
+        RequestError::NegativeServerResponse(ref s, ref d) => {
+            RequestError::NegativeServerResponse(s.clone(), d.clone())
+        }
+        RequestError::BadServerResponse(ref s) => RequestError::BadServerResponse(s.clone()),
+        RequestError::JSONError(ref e) => {
+            RequestError::JSONError(serde::de::Error::custom(e.to_string()))
+        }
+        RequestError::UserError(ref s) => RequestError::UserError(s.clone()),
+        RequestError::LowLevelError(ref e) => {
+            RequestError::LowLevelError(io::Error::new(e.kind(), e.to_string()))
+        }
+        RequestError::Poll(ref pe) => match *pe {
+            PollError::HttpError(ref e) => connection_error(e),
+            PollError::Expired(t) => RequestError::Poll(PollError::Expired(t)),
+            PollError::AccessDenied => RequestError::Poll(PollError::AccessDenied),
+            PollError::TimedOut => RequestError::Poll(PollError::TimedOut),
+            PollError::Other(ref s) => RequestError::Poll(PollError::Other(s.clone())),
+        },
+        RequestError::Refresh(ref rr) => match *rr {
+            RefreshResult::Error(ref e) => connection_error(e),
+            RefreshResult::RefreshError(ref s, ref d) => {
+                RequestError::Refresh(RefreshResult::RefreshError(s.clone(), d.clone()))
+            }
+            RefreshResult::Success(ref t) => {
+                RequestError::Refresh(RefreshResult::Success(t.clone()))
+            }
+        },
+        RequestError::Cache(ref e) => {
+            RequestError::Cache(Box::new(StringError::new(e.to_string(), None)))
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Ok(mut requests) = self.requests.lock() {
+                requests.remove(&self.scope_key);
+            }
+        }
+    }
 }
 
 /// A trait implemented for any hyper::Client as well as teh DefaultHyperClient.
@@ -75,7 +204,7 @@ pub trait AuthFlow<C> {
 /// disk.
 pub struct Authenticator<
     T: AuthFlow<C::Connector>,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: HyperClientBuilder,
 > {
@@ -83,6 +212,8 @@ pub struct Authenticator<
     token_getter: T,
     store: io::Result<S>,
     delegate: AD,
+    revoke_url: String,
+    background_refresh: Option<BackgroundRefresh>,
 }
 
 impl<T> Authenticator<T, MemoryStorage, DefaultAuthenticatorDelegate, DefaultHyperClient>
@@ -109,6 +240,8 @@ where
             token_getter: flow,
             store: Ok(MemoryStorage::new()),
             delegate: DefaultAuthenticatorDelegate,
+            revoke_url: GOOGLE_REVOKE_URL.to_string(),
+            background_refresh: None,
         }
     }
 }
@@ -116,7 +249,7 @@ where
 impl<T, S, AD, C> Authenticator<T, S, AD, C>
 where
     T: AuthFlow<C::Connector>,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: HyperClientBuilder,
 {
@@ -134,6 +267,8 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: self.delegate,
+            revoke_url: self.revoke_url,
+            background_refresh: self.background_refresh,
         }
     }
 
@@ -148,6 +283,25 @@ where
             token_getter: self.token_getter,
             store: disk_storage,
             delegate: self.delegate,
+            revoke_url: self.revoke_url,
+            background_refresh: self.background_refresh,
+        }
+    }
+
+    /// Use the provided token storage instead of the in-memory default. This allows plugging in
+    /// any `TokenStorage` or `AsyncTokenStorage` implementation, e.g. one backed by a database or
+    /// a secret manager.
+    pub fn with_storage<NewS: AsyncTokenStorage>(
+        self,
+        store: NewS,
+    ) -> Authenticator<T, NewS, AD, C> {
+        Authenticator {
+            client: self.client,
+            token_getter: self.token_getter,
+            store: Ok(store),
+            delegate: self.delegate,
+            revoke_url: self.revoke_url,
+            background_refresh: self.background_refresh,
         }
     }
 
@@ -161,6 +315,37 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: delegate,
+            revoke_url: self.revoke_url,
+            background_refresh: self.background_refresh,
+        }
+    }
+
+    /// Use the provided token revocation endpoint, e.g. a `Provider`'s `revocation_uri`. The
+    /// default is `GOOGLE_REVOKE_URL`.
+    pub fn revoke_url(self, url: String) -> Self {
+        Authenticator {
+            revoke_url: url,
+            ..self
+        }
+    }
+
+    /// Refresh tokens in the background before they expire, so that `token()` rarely has to wait
+    /// for the server. A token is refreshed `lead_time` plus a random duration of up to `jitter`
+    /// before it expires; the jitter keeps several processes sharing a token storage from
+    /// refreshing at the same moment. Failures are reported to
+    /// `AuthenticatorDelegate::background_refresh_failed()`.
+    ///
+    /// Only tokens with a refresh token and a lifetime longer than the lead time are refreshed.
+    /// The refresh runs on the tokio executor `token()` was called from, and stops once the
+    /// authenticator is dropped.
+    pub fn background_refresh(self, lead_time: Duration, jitter: Duration) -> Self {
+        Authenticator {
+            background_refresh: Some(BackgroundRefresh {
+                lead_time,
+                jitter,
+                scheduled: Arc::new(Mutex::new(HashSet::new())),
+            }),
+            ..self
         }
     }
 
@@ -183,13 +368,289 @@ where
             inner,
             store,
             delegate: self.delegate,
+            revoke_url: self.revoke_url,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            scope_sets: Arc::new(Mutex::new(HashMap::new())),
+            background_refresh: self.background_refresh,
         })
     }
 }
 
+/// Configuration and state of the background refresh, see `Authenticator::background_refresh()`.
+#[derive(Clone)]
+struct BackgroundRefresh {
+    lead_time: Duration,
+    jitter: Duration,
+    /// Scope hashes a refresh is scheduled for.
+    scheduled: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl BackgroundRefresh {
+    /// How long before the expiry of a token it is to be refreshed.
+    fn lead_time(&self) -> Duration {
+        let jitter_ms = self.jitter.as_millis() as u64;
+        let mut random = [0; 8];
+        if jitter_ms == 0 || SystemRandom::new().fill(&mut random).is_err() {
+            return self.lead_time;
+        }
+        self.lead_time + Duration::from_millis(u64::from_le_bytes(random) % (jitter_ms + 1))
+    }
+}
+
+/// Everything needed to refresh tokens in the background. Only a weak reference to the token
+/// storage is kept, so that the refresh stops once the authenticator is dropped.
+struct RefreshContext<S, AD, C> {
+    config: BackgroundRefresh,
+    store: Weak<Mutex<S>>,
+    in_flight: Arc<Mutex<HashMap<u64, Waiters>>>,
+    client: hyper::Client<C>,
+    appsecret: ApplicationSecret,
+    delegate: AD,
+}
+
+/// A background task.
+type Task = Box<dyn Future<Item = (), Error = ()> + Send>;
+/// A background refresh, yielding the refreshed token and its scopes unless there was nothing to
+/// refresh.
+type RefreshFuture =
+    Box<dyn Future<Item = Option<(Token, Vec<String>)>, Error = RequestError> + Send>;
+
+/// Waits until no token request for `scope_key` is in progress and marks one as in progress.
+fn claim_in_flight(
+    requests: Arc<Mutex<HashMap<u64, Waiters>>>,
+    scope_key: u64,
+) -> impl Future<Item = InFlight, Error = ()> + Send {
+    future::loop_fn((), move |()| {
+        let mut in_flight = requests.lock().unwrap();
+        if let Some(waiters) = in_flight.get_mut(&scope_key) {
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            return future::Either::A(rx.then(|_| Ok(future::Loop::Continue(()))));
+        }
+        in_flight.insert(scope_key, Vec::new());
+        future::Either::B(future::ok(future::Loop::Break(InFlight {
+            requests: requests.clone(),
+            scope_key,
+            done: false,
+        })))
+    })
+}
+
+/// Schedules a refresh of the token stored for `scope_key`, unless one is scheduled already.
+/// After a successful refresh, the next one is scheduled. The refresh counts as a request in
+/// progress for `scope_key`: it waits for a concurrent `token()` call to finish, and `token()`
+/// calls made while it runs wait for its result.
+fn schedule_refresh<S, AD, C>(
+    context: RefreshContext<S, AD, C>,
+    scope_key: u64,
+    scopes: Vec<String>,
+    token: &Token,
+) where
+    S: 'static + AsyncTokenStorage + Send,
+    AD: 'static + AuthenticatorDelegate + Send,
+    C: 'static + hyper::client::connect::Connect + Clone + Send,
+{
+    let RefreshContext {
+        config,
+        store,
+        in_flight,
+        client,
+        appsecret,
+        delegate,
+    } = context;
+    match token.refresh_token {
+        Some(ref rt) if !rt.is_empty() => {}
+        _ => return,
+    }
+    let remaining = match token.expiry_date().map(|e| (e - Utc::now()).to_std()) {
+        Some(Ok(remaining)) => remaining,
+        _ => return,
+    };
+    let wait = match remaining.checked_sub(config.lead_time()) {
+        Some(wait) if wait > Duration::from_secs(0) => wait,
+        // Refreshing now would yield a token that had to be refreshed right away again.
+        _ => return,
+    };
+    if !config.scheduled.lock().unwrap().insert(scope_key) {
+        return;
+    }
+
+    let scheduled = config.scheduled.clone();
+    let requests = in_flight.clone();
+    let task = tokio_timer::sleep(wait).then(move |_| claim_in_flight(requests, scope_key));
+    let task = task.and_then(move |claim| -> Task {
+        config.scheduled.lock().unwrap().remove(&scope_key);
+        // The authenticator has been dropped.
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => return Box::new(future::ok(())),
+        };
+        let weak_store = Arc::downgrade(&store);
+        let scope_refs = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let lookup = store.lock().unwrap().get_async(scope_key, &scope_refs);
+        let refresh_client = client.clone();
+        let refresh_secret = appsecret.clone();
+        let mut delegate = delegate.clone();
+        let refresh = lookup
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+            .and_then(move |stored| -> RefreshFuture {
+                let refresh_token = match stored.and_then(|t| t.refresh_token) {
+                    Some(rt) => rt,
+                    // The token has been revoked in the meantime.
+                    None => return Box::new(future::ok(None)),
+                };
+                Box::new(
+                    RefreshFlow::refresh_token(refresh_client, refresh_secret, refresh_token)
+                        .and_then(|rr| match rr {
+                            RefreshResult::Success(t) => Ok(t),
+                            _ => Err(RequestError::Refresh(rr)),
+                        })
+                        .and_then(move |t| {
+                            let scope_refs = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                            store
+                                .lock()
+                                .unwrap()
+                                .set_async(scope_key, &scope_refs, Some(t.clone()))
+                                .map_err(|e| RequestError::Cache(Box::new(e)))
+                                .map(move |()| Some((t, scopes)))
+                        }),
+                )
+            });
+        Box::new(refresh.then(move |r| {
+            match r {
+                Ok(Some((t, scopes))) => {
+                    let _ = claim.complete(Ok(t.clone()));
+                    let context = RefreshContext {
+                        config,
+                        store: weak_store,
+                        in_flight,
+                        client,
+                        appsecret,
+                        delegate,
+                    };
+                    schedule_refresh(context, scope_key, scopes, &t)
+                }
+                // Dropping `claim` lets waiting `token()` calls start over on their own.
+                Ok(None) => {}
+                Err(e) => delegate.background_refresh_failed(&e),
+            }
+            Ok(())
+        }))
+    });
+    if DefaultExecutor::current().spawn(Box::new(task)).is_err() {
+        scheduled.lock().unwrap().remove(&scope_key);
+    }
+}
+
+type LoopFuture = Box<dyn Future<Item = future::Loop<Token, ()>, Error = RequestError> + Send>;
+
+/// Saves a freshly obtained token and ends the token loop. If the storage fails, the delegate
+/// decides whether to hand out the token anyway, to abort, or to start over after a while.
+fn store_token<S, AD>(
+    store: &Arc<Mutex<S>>,
+    mut delegate: AD,
+    scope_key: u64,
+    scopes: &[String],
+    token: Token,
+) -> LoopFuture
+where
+    S: AsyncTokenStorage,
+    AD: 'static + AuthenticatorDelegate + Send,
+{
+    let set = store.lock().unwrap().set_async(
+        scope_key,
+        &scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+        Some(token.clone()),
+    );
+    Box::new(set.then(move |r| -> LoopFuture {
+        match r {
+            Ok(()) => Box::new(Ok(future::Loop::Break(token)).into_future()),
+            Err(e) => match delegate.token_storage_failure(true, &e) {
+                Retry::Skip => Box::new(Ok(future::Loop::Break(token)).into_future()),
+                Retry::Abort => Box::new(Err(RequestError::Cache(Box::new(e))).into_future()),
+                Retry::After(d) => {
+                    Box::new(tokio_timer::sleep(d).then(|_| Ok(future::Loop::Continue(()))))
+                }
+            },
+        }
+    }))
+}
+
+/// Removes a token that can not be refreshed from the storage. Unless the delegate vetoes it,
+/// the token loop starts over, obtaining a new authorization from the flow; otherwise, it fails
+/// with `error`.
+fn reauthorize<S, AD>(
+    store: &Arc<Mutex<S>>,
+    mut delegate: AD,
+    scope_key: u64,
+    scopes: &[String],
+    error: RequestError,
+) -> LoopFuture
+where
+    S: AsyncTokenStorage,
+    AD: 'static + AuthenticatorDelegate + Send,
+{
+    let evict = store.lock().unwrap().set_async(
+        scope_key,
+        &scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+        None,
+    );
+    Box::new(
+        evict
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+            .and_then(move |()| {
+                if delegate.reauthorize(&error) {
+                    Ok(future::Loop::Continue(()))
+                } else {
+                    Err(error)
+                }
+            }),
+    )
+}
+
+/// Removes the tokens stored for `scope_sets` that carry `refresh_token`, which has been revoked.
+fn evict_grant<S>(
+    store: Arc<Mutex<S>>,
+    scope_sets: Vec<(u64, Vec<String>)>,
+    refresh_token: String,
+) -> Box<dyn Future<Item = (), Error = RequestError> + Send>
+where
+    S: 'static + AsyncTokenStorage + Send,
+{
+    let evictions = scope_sets.into_iter().map(move |(scope_key, scopes)| {
+        let store = store.clone();
+        let refresh_token = refresh_token.clone();
+        let scope_refs = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let lookup = store.lock().unwrap().get_async(scope_key, &scope_refs);
+        lookup
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+            .and_then(
+                move |stored| -> Box<dyn Future<Item = (), Error = RequestError> + Send> {
+                    match stored {
+                        Some(Token {
+                            refresh_token: Some(ref rt),
+                            ..
+                        }) if *rt == refresh_token => {
+                            let scope_refs = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                            Box::new(
+                                store
+                                    .lock()
+                                    .unwrap()
+                                    .set_async(scope_key, &scope_refs, None)
+                                    .map_err(|e| RequestError::Cache(Box::new(e))),
+                            )
+                        }
+                        _ => Box::new(future::ok(())),
+                    }
+                },
+            )
+    });
+    Box::new(future::join_all(evictions).map(|_| ()))
+}
+
 impl<
         GT: 'static + GetToken + Send,
-        S: 'static + TokenStorage + Send,
+        S: 'static + AsyncTokenStorage + Send,
         AD: 'static + AuthenticatorDelegate + Send,
         C: 'static + hyper::client::connect::Connect + Clone + Send,
     > GetToken for AuthenticatorImpl<GT, S, AD, C>
@@ -212,122 +673,689 @@ impl<
         I: IntoIterator<Item = T>,
     {
         let (scope_key, scopes) = hash_scopes(scopes);
+        self.scope_sets
+            .lock()
+            .unwrap()
+            .entry(scope_key)
+            .or_insert_with(|| scopes.clone());
+
+        // If a request for these scopes is already in progress, wait for its result.
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(waiters) = in_flight.get_mut(&scope_key) {
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            let mut this = self.clone();
+            return Box::new(rx.then(
+                move |r| -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+                    match r {
+                        Ok(Ok(t)) => Box::new(future::ok(t)),
+                        Ok(Err(e)) => Box::new(future::err(e)),
+                        // The request was dropped before it completed.
+                        Err(oneshot::Canceled) => this.token(scopes),
+                    }
+                },
+            ));
+        }
+        in_flight.insert(scope_key, Vec::new());
+        drop(in_flight);
+        let in_flight = InFlight {
+            requests: self.in_flight.clone(),
+            scope_key,
+            done: false,
+        };
+
         let store = self.store.clone();
-        let mut delegate = self.delegate.clone();
+        let delegate = self.delegate.clone();
         let client = self.client.clone();
         let appsecret = self.inner.lock().unwrap().application_secret();
         let gettoken = self.inner.clone();
-        let loopfn = move |()| -> Box<
-            dyn Future<Item = future::Loop<Token, ()>, Error = RequestError> + Send,
-        > {
-            // How well does this work with tokio?
-            match store.lock().unwrap().get(
-                scope_key.clone(),
-                &scopes.iter().map(|s| s.as_str()).collect(),
-            ) {
-                Ok(Some(t)) => {
-                    if !t.expired() {
-                        return Box::new(Ok(future::Loop::Break(t)).into_future());
-                    }
-                    // Implement refresh flow.
-                    let refresh_token = t.refresh_token.clone();
-                    let mut delegate = delegate.clone();
-                    let store = store.clone();
-                    let scopes = scopes.clone();
-                    let refresh_fut = RefreshFlow::refresh_token(
-                        client.clone(),
-                        appsecret.clone(),
-                        refresh_token.unwrap(),
-                    )
-                        .and_then(move |rr| -> Box<dyn Future<Item=future::Loop<Token, ()>, Error=RequestError> + Send> {
-                            match rr {
-                                RefreshResult::Error(ref e) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{}", e.description().to_string()),
-                                        &Some("the request has likely timed out".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
-                                }
-                                RefreshResult::RefreshError(ref s, ref ss) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{} {}", s, ss.clone().map(|s| format!("({})", s)).unwrap_or("".to_string())),
-                                        &Some("the refresh token is likely invalid and your authorization has been revoked".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
-                                }
-                                RefreshResult::Success(t) => {
-                                    if let Err(e) = store.lock().unwrap().set(scope_key, &scopes.iter().map(|s| s.as_str()).collect(), Some(t.clone())) {
-                                        match delegate.token_storage_failure(true, &e) {
-                                            Retry::Skip => Box::new(Ok(future::Loop::Break(t)).into_future()),
-                                            Retry::Abort => Box::new(Err(RequestError::Cache(Box::new(e))).into_future()),
-                                            Retry::After(d) => Box::new(
-                                                tokio_timer::sleep(d)
-                                                .then(|_| Ok(future::Loop::Continue(()))),
-                                                )
-                                                as Box<
-                                                dyn Future<
-                                                Item = future::Loop<Token, ()>,
-                                                Error = RequestError> + Send>,
-                                        }
-                                    } else {
-                                        Box::new(Ok(future::Loop::Break(t)).into_future())
-                                    }
-                                },
+        let refresh_context = self
+            .background_refresh
+            .clone()
+            .map(|config| RefreshContext {
+                config,
+                store: Arc::downgrade(&self.store),
+                in_flight: self.in_flight.clone(),
+                client: self.client.clone(),
+                appsecret: appsecret.clone(),
+                delegate: self.delegate.clone(),
+            });
+        let refresh_scopes = scopes.clone();
+        let loopfn = move |()| -> LoopFuture {
+            let lookup = store.lock().unwrap().get_async(
+                scope_key,
+                &scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+            );
+            let store = store.clone();
+            let scopes = scopes.clone();
+            let mut delegate = delegate.clone();
+            let client = client.clone();
+            let appsecret = appsecret.clone();
+            let gettoken = gettoken.clone();
+            Box::new(lookup.then(move |stored| -> LoopFuture {
+                match stored {
+                    Ok(Some(t)) => {
+                        if !t.expired() {
+                            return Box::new(Ok(future::Loop::Break(t)).into_future());
+                        }
+                        // Implement refresh flow.
+                        let refresh_token = match t.refresh_token {
+                            Some(ref rt) if !rt.is_empty() => rt.clone(),
+                            _ => {
+                                let err = RequestError::UserError(
+                                    "the stored token has expired and has no refresh token"
+                                        .to_string(),
+                                );
+                                return reauthorize(&store, delegate, scope_key, &scopes, err);
                             }
-                        });
-                    Box::new(refresh_fut)
-                }
-                Ok(None) => {
-                    let store = store.clone();
-                    let scopes = scopes.clone();
-                    let mut delegate = delegate.clone();
-                    Box::new(
+                        };
+                        let refresh_fut = RefreshFlow::refresh_token(
+                            client,
+                            appsecret,
+                            refresh_token,
+                        )
+                            .and_then(move |rr| -> LoopFuture {
+                                match rr {
+                                    RefreshResult::Error(ref e) => {
+                                        delegate.token_refresh_failed(
+                                            format!("{}", e.description().to_string()),
+                                            &Some("the request has likely timed out".to_string()),
+                                            );
+                                        Box::new(Err(RequestError::Refresh(rr)).into_future())
+                                    }
+                                    RefreshResult::RefreshError(ref s, ref ss) => {
+                                        delegate.token_refresh_failed(
+                                            format!("{} {}", s, ss.clone().map(|s| format!("({})", s)).unwrap_or("".to_string())),
+                                            &Some("the refresh token is likely invalid and your authorization has been revoked".to_string()),
+                                            );
+                                        reauthorize(&store, delegate, scope_key, &scopes, RequestError::Refresh(rr))
+                                    }
+                                    RefreshResult::Success(t) => {
+                                        store_token(&store, delegate, scope_key, &scopes, t)
+                                    },
+                                }
+                            });
+                        Box::new(refresh_fut)
+                    }
+                    Ok(None) => Box::new(
                         gettoken
                             .lock()
                             .unwrap()
                             .token(scopes.clone())
-                            .and_then(move |t| {
-                                if let Err(e) = store.lock().unwrap().set(
-                                    scope_key,
-                                    &scopes.iter().map(|s| s.as_str()).collect(),
-                                    Some(t.clone()),
-                                ) {
-                                    match delegate.token_storage_failure(true, &e) {
-                                        Retry::Skip => {
-                                            Box::new(Ok(future::Loop::Break(t)).into_future())
-                                        }
-                                        Retry::Abort => Box::new(
-                                            Err(RequestError::Cache(Box::new(e))).into_future(),
-                                        ),
-                                        Retry::After(d) => Box::new(
-                                            tokio_timer::sleep(d)
-                                                .then(|_| Ok(future::Loop::Continue(()))),
+                            .and_then(move |t| store_token(&store, delegate, scope_key, &scopes, t)),
+                    ),
+                    Err(err) => match delegate.token_storage_failure(false, &err) {
+                        Retry::Abort | Retry::Skip => {
+                            Box::new(Err(RequestError::Cache(Box::new(err))).into_future())
+                        }
+                        Retry::After(d) => Box::new(
+                            tokio_timer::sleep(d).then(|_| Ok(future::Loop::Continue(()))),
+                        ),
+                    },
+                }
+            }))
+        };
+        Box::new(future::loop_fn((), loopfn).then(move |r| {
+            if let (Ok(ref t), Some(context)) = (&r, refresh_context) {
+                schedule_refresh(context, scope_key, refresh_scopes, t);
+            }
+            in_flight.complete(r)
+        }))
+    }
+
+    /// Revokes the token stored for the given scopes and removes it from the token storage.
+    /// If a refresh token is available, it is revoked, which invalidates the entire grant: The
+    /// tokens of all other scope sets requested from this authenticator that carry the same
+    /// refresh token are removed as well. Tokens of the grant stored for scope sets this
+    /// authenticator hasn't been asked for can't be refreshed anymore; they are replaced by
+    /// running the flow again (see `AuthenticatorDelegate::reauthorize()`).
+    fn revoke<I, T>(&mut self, scopes: I) -> Box<dyn Future<Item = (), Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (scope_key, scopes) = hash_scopes(scopes);
+        let lookup = self.store.lock().unwrap().get_async(
+            scope_key,
+            &scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+        );
+        let store = self.store.clone();
+        let client = self.client.clone();
+        let revoke_url = self.revoke_url.clone();
+        let appsecret = self.inner.lock().unwrap().application_secret();
+        let other_scope_sets: Vec<(u64, Vec<String>)> = self
+            .scope_sets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(&key, _)| key != scope_key)
+            .map(|(&key, scopes)| (key, scopes.clone()))
+            .collect();
+        Box::new(
+            lookup
+                .map_err(|e| RequestError::Cache(Box::new(e)))
+                .and_then(
+                    move |stored| -> Box<dyn Future<Item = (), Error = RequestError> + Send> {
+                        let token = match stored {
+                            Some(t) => t,
+                            // Nothing to revoke.
+                            None => return Box::new(future::ok(())),
+                        };
+                        let (token, hint) = match token.refresh_token {
+                            Some(ref rt) if !rt.is_empty() => {
+                                (rt.clone(), TokenTypeHint::RefreshToken)
+                            }
+                            _ => (token.access_token, TokenTypeHint::AccessToken),
+                        };
+                        // Only a revoked refresh token affects the tokens of other scope sets.
+                        let revoked_grant = match hint {
+                            TokenTypeHint::RefreshToken => Some(token.clone()),
+                            TokenTypeHint::AccessToken => None,
+                        };
+                        let grant_store = store.clone();
+                        Box::new(
+                            RevokeFlow::revoke_token(client, &revoke_url, &appsecret, token, hint)
+                                .and_then(move |()| {
+                                    store
+                                        .lock()
+                                        .unwrap()
+                                        .set_async(
+                                            scope_key,
+                                            &scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+                                            None,
                                         )
-                                            as Box<
-                                                dyn Future<
-                                                        Item = future::Loop<Token, ()>,
-                                                        Error = RequestError,
-                                                    > + Send,
-                                            >,
+                                        .map_err(|e| RequestError::Cache(Box::new(e)))
+                                })
+                                .and_then(move |()| match revoked_grant {
+                                    Some(refresh_token) => {
+                                        evict_grant(grant_store, other_scope_sets, refresh_token)
                                     }
-                                } else {
-                                    Box::new(Ok(future::Loop::Break(t)).into_future())
-                                }
-                            }),
-                    )
-                }
-                Err(err) => match delegate.token_storage_failure(false, &err) {
-                    Retry::Abort | Retry::Skip => {
-                        return Box::new(Err(RequestError::Cache(Box::new(err))).into_future())
-                    }
-                    Retry::After(d) => {
-                        return Box::new(
-                            tokio_timer::sleep(d).then(|_| Ok(future::Loop::Continue(()))),
+                                    None => Box::new(future::ok(())),
+                                }),
                         )
-                    }
-                },
+                    },
+                ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{NullError, TokenStorage};
+
+    /// A flow handing out a fixed token (or failing, if `fail` is set) after a short delay,
+    /// counting how often it was asked for one.
+    #[derive(Clone)]
+    struct FixedTokenFlow {
+        token: Token,
+        calls: Arc<Mutex<usize>>,
+        fail: bool,
+    }
+
+    impl<C> AuthFlow<C> for FixedTokenFlow {
+        type TokenGetter = Self;
+
+        fn build_token_getter(self, _: hyper::Client<C>) -> Self {
+            self
+        }
+    }
+
+    impl GetToken for FixedTokenFlow {
+        fn token<I, T>(
+            &mut self,
+            _: I,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            *self.calls.lock().unwrap() += 1;
+            let result = if self.fail {
+                Err(RequestError::UserError("the flow failed".to_string()))
+            } else {
+                Ok(self.token.clone())
+            };
+            Box::new(tokio_timer::sleep(std::time::Duration::from_millis(20)).then(move |_| result))
+        }
+        fn api_key(&mut self) -> Option<String> {
+            None
+        }
+        fn application_secret(&self) -> ApplicationSecret {
+            ApplicationSecret {
+                client_id: "myclient".to_string(),
+                client_secret: "mysecret".to_string(),
+                token_uri: format!("{}/authenticator/token", mockito::server_url()),
+                ..Default::default()
             }
-        };
-        Box::new(future::loop_fn((), loopfn))
+        }
+    }
+
+    fn fixed_token_flow() -> FixedTokenFlow {
+        FixedTokenFlow {
+            token: Token {
+                access_token: "accesstoken".to_string(),
+                refresh_token: Some("refreshtoken".to_string()),
+                token_type: "Bearer".to_string(),
+                expires_in: None,
+                expires_in_timestamp: Some(chrono::Utc::now().timestamp() + 3600),
+                scope: None,
+                id_token: None,
+            },
+            calls: Arc::new(Mutex::new(0)),
+            fail: false,
+        }
+    }
+
+    #[test]
+    fn test_revoke() {
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let mut auth = Authenticator::new(flow)
+            .revoke_url(format!("{}/revoke", mockito::server_url()))
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+        let other_scopes = vec!["https://googleapis.com/other/scope"];
+
+        rt.block_on(auth.token(scopes.clone())).unwrap();
+        rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        // A token of the same grant, for other scopes.
+        rt.block_on(auth.token(other_scopes.clone())).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        let _m = mockito::mock("POST", "/revoke")
+            .match_body(mockito::Matcher::Regex(
+                "^token=refreshtoken&token_type_hint=refresh_token.*".to_string(),
+            ))
+            .with_status(200)
+            .expect(1)
+            .create();
+        rt.block_on(auth.revoke(scopes.clone())).unwrap();
+        // Nothing left to revoke.
+        rt.block_on(auth.revoke(scopes.clone())).unwrap();
+        _m.assert();
+
+        // The revoked token is not used anymore, and neither is the other token of the grant.
+        rt.block_on(auth.token(scopes)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 3);
+        rt.block_on(auth.token(other_scopes)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_with_storage() {
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+        let (hash, sorted) = hash_scopes(scopes.clone());
+
+        let mut store = MemoryStorage::new();
+        let mut stored = flow.token.clone();
+        stored.access_token = "storedtoken".to_string();
+        store
+            .set(
+                hash,
+                &sorted.iter().map(|s| s.as_str()).collect(),
+                Some(stored),
+            )
+            .unwrap();
+
+        let mut auth = Authenticator::new(flow)
+            .with_storage(store)
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let tok = rt.block_on(auth.token(scopes)).unwrap();
+        assert_eq!(tok.access_token, "storedtoken");
+        assert_eq!(*calls.lock().unwrap(), 0);
+    }
+
+    /// A storage completing its operations asynchronously, failing the first `set_async`.
+    #[derive(Clone, Default)]
+    struct SlowStorage {
+        tokens: Arc<Mutex<MemoryStorage>>,
+        failed_once: Arc<Mutex<bool>>,
+    }
+
+    impl AsyncTokenStorage for SlowStorage {
+        type Error = io::Error;
+
+        fn set_async(
+            &mut self,
+            scope_hash: u64,
+            scopes: &[&str],
+            token: Option<Token>,
+        ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+            let tokens = self.tokens.clone();
+            let failed_once = self.failed_once.clone();
+            let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+            Box::new(
+                tokio_timer::sleep(std::time::Duration::from_millis(10))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .and_then(move |()| {
+                        if !std::mem::replace(&mut *failed_once.lock().unwrap(), true) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "storage unavailable",
+                            ));
+                        }
+                        let scopes = scopes.iter().map(|s| s.as_str()).collect();
+                        let _ = tokens.lock().unwrap().set(scope_hash, &scopes, token);
+                        Ok(())
+                    }),
+            )
+        }
+
+        fn get_async(
+            &self,
+            scope_hash: u64,
+            scopes: &[&str],
+        ) -> Box<dyn Future<Item = Option<Token>, Error = io::Error> + Send> {
+            let tokens = self.tokens.clone();
+            let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+            Box::new(
+                tokio_timer::sleep(std::time::Duration::from_millis(10))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .and_then(move |()| {
+                        let scopes = scopes.iter().map(|s| s.as_str()).collect();
+                        Ok(tokens.lock().unwrap().get(scope_hash, &scopes).unwrap())
+                    }),
+            )
+        }
+    }
+
+    #[derive(Clone)]
+    struct RetryingDelegate;
+
+    impl AuthenticatorDelegate for RetryingDelegate {
+        fn token_storage_failure(&mut self, _: bool, _: &dyn Error) -> Retry {
+            Retry::After(std::time::Duration::from_millis(1))
+        }
+    }
+
+    #[test]
+    fn test_async_storage() {
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let storage = SlowStorage::default();
+        let mut auth = Authenticator::new(flow)
+            .with_storage(storage.clone())
+            .delegate(RetryingDelegate)
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+
+        // The first attempt to store the token fails, so the flow runs twice.
+        rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+        rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        let (hash, sorted) = hash_scopes(scopes);
+        let stored = storage
+            .tokens
+            .lock()
+            .unwrap()
+            .get(hash, &sorted.iter().map(|s| s.as_str()).collect())
+            .unwrap();
+        assert_eq!(stored.unwrap().access_token, "accesstoken");
+    }
+
+    #[test]
+    fn test_concurrent_requests_share_result() {
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let mut auth = Authenticator::new(flow).build().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+
+        let requests: Vec<_> = (0..5).map(|_| auth.token(scopes.clone())).collect();
+        let tokens = rt.block_on(future::join_all(requests)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert!(tokens.iter().all(|t| t.access_token == "accesstoken"));
+
+        // Requests for other scopes are not held up.
+        let other = auth.token(vec!["https://googleapis.com/other/scope"]);
+        let same = auth.token(scopes);
+        rt.block_on(other.join(same)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_requests_share_error() {
+        let mut flow = fixed_token_flow();
+        flow.fail = true;
+        let calls = flow.calls.clone();
+        let mut auth = Authenticator::new(flow).build().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+
+        let requests: Vec<_> = (0..3)
+            .map(|_| auth.token(scopes.clone()).then(Ok::<_, ()>))
+            .collect();
+        let results = rt.block_on(future::join_all(requests)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        for r in results {
+            match r {
+                Err(RequestError::UserError(ref e)) => assert_eq!(e, "the flow failed"),
+                other => panic!("unexpected result {:?}", other.map(|t| t.access_token)),
+            }
+        }
+
+        // A single caller gets the same error.
+        match rt.block_on(auth.token(scopes.clone())) {
+            Err(RequestError::UserError(_)) => {}
+            other => panic!("unexpected result {:?}", other.map(|t| t.access_token)),
+        }
+
+        // Dropping the first request lets the waiting one go ahead on its own.
+        let first = auth.token(scopes.clone());
+        let second = auth.token(scopes);
+        drop(first);
+        assert!(rt.block_on(second).is_err());
+        assert_eq!(*calls.lock().unwrap(), 3);
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingDelegate {
+        background_failures: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AuthenticatorDelegate for RecordingDelegate {
+        fn background_refresh_failed(&mut self, e: &RequestError) {
+            self.background_failures
+                .lock()
+                .unwrap()
+                .push(format!("{}", e));
+        }
+    }
+
+    #[test]
+    fn test_background_refresh() {
+        let mut flow = fixed_token_flow();
+        // Choose the lead time so that the token is refreshed after about 100ms.
+        let now = chrono::Utc::now();
+        let expiry = now.timestamp() + 65;
+        flow.token.expires_in_timestamp = Some(expiry);
+        let lead_time =
+            Duration::from_millis((expiry * 1000 - now.timestamp_millis()) as u64 - 100);
+        let calls = flow.calls.clone();
+        let delegate = RecordingDelegate::default();
+        let failures = delegate.background_failures.clone();
+        let mut auth = Authenticator::new(flow)
+            .delegate(delegate)
+            .background_refresh(lead_time, Duration::from_millis(0))
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://googleapis.com/some/scope"];
+
+        let _m = mockito::mock("POST", "/authenticator/token")
+            .match_body(mockito::Matcher::Regex(
+                ".*refresh_token=refreshtoken.*".to_string(),
+            ))
+            .with_status(200)
+            .with_body(
+                r#"{"access_token": "refreshed", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+
+        let tok = rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(tok.access_token, "accesstoken");
+        rt.block_on(tokio_timer::sleep(Duration::from_millis(300)))
+            .unwrap();
+        _m.assert();
+
+        // The refreshed token is used without contacting the server.
+        let tok = rt.block_on(auth.token(scopes)).unwrap();
+        assert_eq!(tok.access_token, "refreshed");
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert!(failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_background_refresh_waits_for_request_in_flight() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+        let first = rt.block_on(claim_in_flight(requests.clone(), 1)).unwrap();
+        let mut second = claim_in_flight(requests.clone(), 1);
+        let other = claim_in_flight(requests.clone(), 2);
+        let second = rt
+            .block_on(future::lazy(move || {
+                assert!(second.poll().unwrap().is_not_ready());
+                // Other scopes are not held up.
+                other.map(move |_| second)
+            }))
+            .unwrap();
+
+        drop(first);
+        let second = rt.block_on(second).unwrap();
+        assert!(requests.lock().unwrap().contains_key(&1));
+        drop(second);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[derive(Clone)]
+    struct VetoingDelegate;
+
+    impl AuthenticatorDelegate for VetoingDelegate {
+        fn reauthorize(&mut self, _: &RequestError) -> bool {
+            false
+        }
+    }
+
+    /// A storage holding an expired token with the given refresh token.
+    fn storage_with_expired_token(
+        scopes: &[&str],
+        refresh_token: Option<&str>,
+    ) -> Arc<Mutex<MemoryStorage>> {
+        let (hash, sorted) = hash_scopes(scopes.iter().cloned());
+        let mut token = fixed_token_flow().token;
+        token.access_token = "expiredtoken".to_string();
+        token.refresh_token = refresh_token.map(str::to_string);
+        token.expires_in_timestamp = Some(chrono::Utc::now().timestamp() - 10);
+        let mut store = MemoryStorage::new();
+        store
+            .set(
+                hash,
+                &sorted.iter().map(|s| s.as_str()).collect(),
+                Some(token),
+            )
+            .unwrap();
+        Arc::new(Mutex::new(store))
+    }
+
+    /// Gives the authenticator access to a storage the test can inspect, too.
+    #[derive(Clone)]
+    struct SharedStorage(Arc<Mutex<MemoryStorage>>);
+
+    impl AsyncTokenStorage for SharedStorage {
+        type Error = NullError;
+
+        fn set_async(
+            &mut self,
+            scope_hash: u64,
+            scopes: &[&str],
+            token: Option<Token>,
+        ) -> Box<dyn Future<Item = (), Error = NullError> + Send> {
+            self.0.lock().unwrap().set_async(scope_hash, scopes, token)
+        }
+        fn get_async(
+            &self,
+            scope_hash: u64,
+            scopes: &[&str],
+        ) -> Box<dyn Future<Item = Option<Token>, Error = NullError> + Send> {
+            self.0.lock().unwrap().get_async(scope_hash, scopes)
+        }
+    }
+
+    #[test]
+    fn test_reauthorize_after_revoked_refresh_token() {
+        let scopes = vec!["https://googleapis.com/some/scope"];
+        let (hash, sorted) = hash_scopes(scopes.clone());
+        let sorted = sorted.iter().map(|s| s.as_str()).collect();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _m = mockito::mock("POST", "/authenticator/token")
+            .match_body(mockito::Matcher::Regex(
+                ".*refresh_token=revokedtoken.*".to_string(),
+            ))
+            .with_status(400)
+            .with_body(r#"{"error": "invalid_grant"}"#)
+            .expect(2)
+            .create();
+
+        // The flow runs again and its token replaces the dead one.
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let store = storage_with_expired_token(&scopes, Some("revokedtoken"));
+        let mut auth = Authenticator::new(flow)
+            .with_storage(SharedStorage(store.clone()))
+            .build()
+            .unwrap();
+        let tok = rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(tok.access_token, "accesstoken");
+        assert_eq!(*calls.lock().unwrap(), 1);
+        let stored = store.lock().unwrap().get(hash, &sorted).unwrap().unwrap();
+        assert_eq!(stored.access_token, "accesstoken");
+
+        // The delegate vetoes running the flow; the dead token is removed nonetheless.
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let store = storage_with_expired_token(&scopes, Some("revokedtoken"));
+        let mut auth = Authenticator::new(flow)
+            .with_storage(SharedStorage(store.clone()))
+            .delegate(VetoingDelegate)
+            .build()
+            .unwrap();
+        match rt.block_on(auth.token(scopes.clone())) {
+            Err(RequestError::Refresh(RefreshResult::RefreshError(ref e, _))) => {
+                assert_eq!(e, "invalid_grant")
+            }
+            other => panic!("unexpected result {:?}", other.map(|t| t.access_token)),
+        }
+        assert_eq!(*calls.lock().unwrap(), 0);
+        assert!(store.lock().unwrap().get(hash, &sorted).unwrap().is_none());
+        _m.assert();
+    }
+
+    #[test]
+    fn test_reauthorize_without_refresh_token() {
+        let scopes = vec!["https://googleapis.com/other/scope"];
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let flow = fixed_token_flow();
+        let calls = flow.calls.clone();
+        let store = storage_with_expired_token(&scopes, None);
+        let mut auth = Authenticator::new(flow)
+            .with_storage(SharedStorage(store))
+            .build()
+            .unwrap();
+        let tok = rt.block_on(auth.token(scopes)).unwrap();
+        assert_eq!(tok.access_token, "accesstoken");
+        assert_eq!(*calls.lock().unwrap(), 1);
     }
 }
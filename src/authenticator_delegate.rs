@@ -30,6 +30,9 @@ pub struct PollInformation {
     pub user_code: String,
     /// ... at the verification URL
     pub verification_url: String,
+    /// A verification URL including the `user_code`, if provided by the server. Users don't
+    /// need to enter the code when opening it, e.g. by scanning a QR code.
+    pub verification_uri_complete: Option<String>,
 
     /// The `user_code` expires at the given time
     /// It's the time the user has left to authenticate your application
@@ -66,6 +69,22 @@ impl Error for PollError {
     }
 }
 
+/// The credentials of a resource owner (user), as used by the `PasswordFlow`.
+#[derive(Clone)]
+pub struct ResourceOwnerCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for ResourceOwnerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ResourceOwnerCredentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 /// A partially implemented trait to interact with the `Authenticator`
 ///
 /// The only method that needs to be implemented manually is `present_user_code(...)`,
@@ -81,8 +100,8 @@ pub trait AuthenticatorDelegate: Clone {
     /// Called whenever we failed to retrieve a token or set a token due to a storage error.
     /// You may use it to either ignore the incident or retry.
     /// This can be useful if the underlying `TokenStorage` may fail occasionally.
-    /// if `is_set` is true, the failure resulted from `TokenStorage.set(...)`. Otherwise,
-    /// it was `TokenStorage.get(...)`
+    /// if `is_set` is true, the failure resulted from `TokenStorage.set(...)` (or
+    /// `AsyncTokenStorage.set_async(...)`). Otherwise, it was a `get`.
     fn token_storage_failure(&mut self, is_set: bool, _: &dyn Error) -> Retry {
         let _ = is_set;
         Retry::Abort
@@ -91,6 +110,22 @@ pub trait AuthenticatorDelegate: Clone {
     /// The server denied the attempt to obtain a request code
     fn request_failure(&mut self, _: RequestError) {}
 
+    /// Called if a stored token can not be refreshed, because the server rejected the refresh
+    /// token (e.g. as the authorization was revoked) or because there is none. The token has been
+    /// removed from the token storage.
+    ///
+    /// Return `true` to run the flow again and obtain a new authorization (which usually
+    /// involves the user), or `false` to fail with `error`.
+    fn reauthorize(&mut self, error: &RequestError) -> bool {
+        let _ = error;
+        true
+    }
+
+    /// Called if refreshing a token in the background failed (see
+    /// `Authenticator::background_refresh()`). The token is refreshed the next time it is
+    /// requested instead.
+    fn background_refresh_failed(&mut self, _: &RequestError) {}
+
     /// Called if we could not acquire a refresh token for a reason possibly specified
     /// by the server.
     /// This call is made for the delegate's information only.
@@ -145,6 +180,9 @@ pub trait FlowDelegate: Clone {
             "Please enter {} at {} and grant access to this application",
             pi.user_code, pi.verification_url
         );
+        if let Some(ref uri) = pi.verification_uri_complete {
+            println!("Alternatively, open {} to skip entering the code.", uri);
+        }
         println!("Do not close this application until you either denied or granted access.");
         println!(
             "You have time until {}.",
@@ -152,6 +190,21 @@ pub trait FlowDelegate: Clone {
         );
     }
 
+    /// This method is used by the PasswordFlow whenever it requests a new token (but not when
+    /// refreshing one). The returned credentials are only used for that request and never
+    /// stored.
+    ///
+    /// The default implementation fails; implement it to ask the user or look up a secret store.
+    fn resource_owner_credentials(
+        &mut self,
+    ) -> Box<dyn Future<Item = ResourceOwnerCredentials, Error = Box<dyn Error + Send>> + Send>
+    {
+        Box::new(future::err(Box::new(io::Error::new(
+            io::ErrorKind::Other,
+            "the FlowDelegate does not provide resource owner credentials",
+        )) as Box<dyn Error + Send>))
+    }
+
     /// This method is used by the InstalledFlow.
     /// We need the user to navigate to a URL using their browser and potentially paste back a code
     /// (or maybe not). Whether they have to enter a code depends on the InstalledFlowReturnMethod
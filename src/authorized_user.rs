@@ -0,0 +1,232 @@
+//! This module provides a token source (`GetToken`) for "authorized user" credentials, as written
+//! by `gcloud auth application-default login`. They consist of a client ID and secret together
+//! with a refresh token; access tokens are obtained through the refresh flow.
+
+use std::sync::{Arc, Mutex};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::refresh::RefreshFlow;
+use crate::types::{ApplicationSecret, GetToken, RefreshResult, RequestError, Token};
+
+use futures::{future, prelude::*};
+
+/// The token endpoint authorized user credentials are refreshed at.
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+/// JSON schema of an authorized user credentials file:
+/// `{"type": "authorized_user", "client_id": ..., "client_secret": ..., "refresh_token": ...}`.
+///
+/// Use `authorized_user_secret_from_file()` to read one from disk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthorizedUserSecret {
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    /// The project to bill API requests to, if it differs from the client's project. Send it as
+    /// the `x-goog-user-project` header.
+    pub quota_project_id: Option<String>,
+}
+
+/// A token source (`GetToken`) yielding OAuth tokens for authorized user credentials. Like
+/// `ServiceAccountAccess`, it caches the token and refreshes it once it has expired, so there is
+/// no need to wrap it in an `Authenticator`.
+///
+/// As the refresh token is bound to the scopes granted when logging in, the scopes passed to
+/// `token()` are not used.
+#[derive(Clone)]
+pub struct AuthorizedUserAccess<C> {
+    client: C,
+    secret: AuthorizedUserSecret,
+}
+
+impl AuthorizedUserAccess<DefaultHyperClient> {
+    /// Create a new AuthorizedUserAccess with the provided secret.
+    pub fn new(secret: AuthorizedUserSecret) -> Self {
+        AuthorizedUserAccess {
+            client: DefaultHyperClient,
+            secret,
+        }
+    }
+}
+
+impl<C> AuthorizedUserAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> AuthorizedUserAccess<NewC> {
+        AuthorizedUserAccess {
+            client: hyper_client,
+            secret: self.secret,
+        }
+    }
+
+    /// The quota project named in the credentials, if any. The built token source doesn't
+    /// keep it; `ApplicationDefaultCredentials` passes it on with
+    /// `BoxedTokenSource::quota_project_id()`.
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.secret.quota_project_id.as_deref()
+    }
+
+    /// Build the configured AuthorizedUserAccess.
+    pub fn build(self) -> impl GetToken {
+        AuthorizedUserAccessImpl::new(self.client.build_hyper_client(), self.secret)
+    }
+}
+
+struct AuthorizedUserAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    secret: AuthorizedUserSecret,
+    token_uri: String,
+    cache: Arc<Mutex<Option<Token>>>,
+}
+
+impl<C> AuthorizedUserAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn new(client: hyper::Client<C>, secret: AuthorizedUserSecret) -> Self {
+        AuthorizedUserAccessImpl {
+            client,
+            secret,
+            token_uri: GOOGLE_TOKEN_URL.to_string(),
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl<C: 'static> GetToken for AuthorizedUserAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let _ = scopes.into_iter();
+        // The provider may have rotated the refresh token of the credentials file.
+        let mut refresh_token = self.secret.refresh_token.clone();
+        if let Some(ref token) = *self.cache.lock().unwrap() {
+            if !token.expired() {
+                return Box::new(future::ok(token.clone()));
+            }
+            if let Some(ref rt) = token.refresh_token {
+                refresh_token = rt.clone();
+            }
+        }
+
+        let cache = self.cache.clone();
+        Box::new(
+            RefreshFlow::refresh_token(
+                self.client.clone(),
+                self.application_secret(),
+                refresh_token,
+            )
+            .and_then(move |rr| match rr {
+                RefreshResult::Success(token) => {
+                    *cache.lock().unwrap() = Some(token.clone());
+                    Ok(token)
+                }
+                _ => Err(RequestError::Refresh(rr)),
+            }),
+        )
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: self.secret.client_id.clone(),
+            client_secret: self.secret.client_secret.clone(),
+            token_uri: self.token_uri.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::authorized_user_secret_from_file;
+
+    use hyper_rustls::HttpsConnector;
+    use mockito::mock;
+
+    #[test]
+    fn test_refresh_and_cache() {
+        let secret: AuthorizedUserSecret = serde_json::from_str(
+            r#"{
+  "client_id": "myclient",
+  "client_secret": "mysecret",
+  "quota_project_id": "myproject",
+  "refresh_token": "my-refresh-token",
+  "type": "authorized_user"
+}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            AuthorizedUserAccess::new(secret.clone()).quota_project_id(),
+            Some("myproject")
+        );
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+        let mut access = AuthorizedUserAccessImpl::new(client, secret);
+        access.token_uri = format!("{}/token", mockito::server_url());
+
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        let _m = mock("POST", "/token")
+            .match_body(mockito::Matcher::Regex(
+                ".*refresh_token=my-refresh-token.*".to_string(),
+            ))
+            .with_status(200)
+            .with_body(
+                r#"{"access_token": "accesstoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        let tok = rt
+            .block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+            .expect("token");
+        assert_eq!(tok.access_token, "accesstoken");
+        // The second call is served from the cache.
+        let tok = rt
+            .block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+            .expect("token");
+        assert_eq!(tok.access_token, "accesstoken");
+        _m.assert();
+    }
+
+    #[test]
+    fn test_read_secret_from_file() {
+        let dir = std::env::temp_dir().join("yup-oauth2-authorized-user-test.json");
+        std::fs::write(
+            &dir,
+            r#"{"type": "authorized_user", "client_id": "id", "client_secret": "secret", "refresh_token": "rt"}"#,
+        )
+        .unwrap();
+        let secret = authorized_user_secret_from_file(&dir).unwrap();
+        std::fs::remove_file(&dir).unwrap();
+        assert_eq!(secret.refresh_token, "rt");
+        assert_eq!(secret.quota_project_id, None);
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::iter::{FromIterator, IntoIterator};
 use std::time::Duration;
 
@@ -14,33 +15,39 @@ use tokio_timer;
 use url::form_urlencoded;
 
 use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate, PollInformation, Retry};
+use crate::provider::{Provider, DEVICE_CODE_GRANT_TYPE, LEGACY_DEVICE_GRANT_TYPE};
 use crate::types::{
     ApplicationSecret, Flow, FlowType, GetToken, JsonError, PollError, RequestError, Token,
 };
 
-pub const GOOGLE_DEVICE_CODE_URL: &'static str = "https://accounts.google.com/o/oauth2/device/code";
+pub const GOOGLE_DEVICE_CODE_URL: &'static str = "https://oauth2.googleapis.com/device/code";
 
-/// Implements the [Oauth2 Device Flow](https://developers.google.com/youtube/v3/guides/authentication#devices)
+/// The interval in which to poll if the server doesn't specify one.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How much to increase the interval by when the server responds with `slow_down`.
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+/// Implements the [OAuth 2.0 Device Authorization Grant](https://tools.ietf.org/html/rfc8628)
 /// It operates in two steps:
 /// * obtain a code to show to the user
 // * (repeatedly) poll for the user to authenticate your application
 #[derive(Clone)]
 pub struct DeviceFlow<FD> {
     application_secret: ApplicationSecret,
-    device_code_url: String,
+    device_code_url: Option<String>,
+    grant_type: String,
     flow_delegate: FD,
-    wait: Duration,
 }
 
 impl DeviceFlow<DefaultFlowDelegate> {
-    /// Create a new DeviceFlow. The default FlowDelegate will be used and the
-    /// default wait time is 120 seconds.
+    /// Create a new DeviceFlow. The default FlowDelegate will be used. The flow polls until the
+    /// user has authorized the application, or the device code has expired.
     pub fn new(secret: ApplicationSecret) -> DeviceFlow<DefaultFlowDelegate> {
         DeviceFlow {
             application_secret: secret,
-            device_code_url: GOOGLE_DEVICE_CODE_URL.to_string(),
+            device_code_url: Some(GOOGLE_DEVICE_CODE_URL.to_string()),
+            grant_type: DEVICE_CODE_GRANT_TYPE.to_string(),
             flow_delegate: DefaultFlowDelegate,
-            wait: Duration::from_secs(120),
         }
     }
 }
@@ -49,7 +56,18 @@ impl<FD> DeviceFlow<FD> {
     /// Use the provided device code url.
     pub fn device_code_url(self, url: String) -> Self {
         DeviceFlow {
-            device_code_url: url,
+            device_code_url: Some(url),
+            ..self
+        }
+    }
+
+    /// Use the provided provider's device authorization and token endpoints (overriding the
+    /// token endpoint of the application secret) and device grant type.
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.application_secret.token_uri = provider.token_uri;
+        DeviceFlow {
+            device_code_url: provider.device_authorization_uri,
+            grant_type: provider.device_grant_type,
             ..self
         }
     }
@@ -59,17 +77,15 @@ impl<FD> DeviceFlow<FD> {
         DeviceFlow {
             application_secret: self.application_secret,
             device_code_url: self.device_code_url,
+            grant_type: self.grant_type,
             flow_delegate: delegate,
-            wait: self.wait,
         }
     }
 
-    /// Use the provided wait duration.
-    pub fn wait_duration(self, duration: Duration) -> Self {
-        DeviceFlow {
-            wait: duration,
-            ..self
-        }
+    /// Has no effect: The flow polls until the device code expires, as told by the server.
+    #[deprecated(note = "the expiry of the device code determines how long the flow polls")]
+    pub fn wait_duration(self, _duration: Duration) -> Self {
+        self
     }
 }
 
@@ -85,8 +101,8 @@ where
             client,
             application_secret: self.application_secret,
             device_code_url: self.device_code_url,
+            grant_type: self.grant_type,
             fd: self.flow_delegate,
-            wait: Duration::from_secs(1200),
         }
     }
 }
@@ -95,10 +111,21 @@ where
 pub struct DeviceFlowImpl<FD, C> {
     client: hyper::Client<C, hyper::Body>,
     application_secret: ApplicationSecret,
-    /// Usually GOOGLE_DEVICE_CODE_URL
-    device_code_url: String,
+    /// Usually GOOGLE_DEVICE_CODE_URL; `None` if the provider has no device authorization
+    /// endpoint.
+    device_code_url: Option<String>,
+    grant_type: String,
     fd: FD,
-    wait: Duration,
+}
+
+/// The result of a single poll of the token endpoint.
+enum PollStatus {
+    /// The user hasn't authorized the application yet.
+    Pending,
+    /// The user hasn't authorized the application yet, and we poll too frequently.
+    SlowDown,
+    /// The user has authorized the application.
+    Done(Token),
 }
 
 impl<FD, C> Flow for DeviceFlowImpl<FD, C> {
@@ -143,14 +170,25 @@ where
         &mut self,
         scopes: Vec<String>,
     ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        let device_code_url = match self.device_code_url {
+            Some(ref url) => url.clone(),
+            None => {
+                return Box::new(
+                    Err(RequestError::UserError(
+                        "the provider has no device authorization endpoint".to_string(),
+                    ))
+                    .into_future(),
+                )
+            }
+        };
         let application_secret = self.application_secret.clone();
         let client = self.client.clone();
-        let wait = self.wait;
+        let grant_type = self.grant_type.clone();
         let mut fd = self.fd.clone();
         let request_code = Self::request_code(
             application_secret.clone(),
             client.clone(),
-            self.device_code_url.clone(),
+            device_code_url,
             scopes,
         )
         .and_then(move |(pollinf, device_code)| {
@@ -159,51 +197,51 @@ where
         });
         let fd = self.fd.clone();
         Box::new(request_code.and_then(move |(pollinf, device_code)| {
-            future::loop_fn(0, move |i| {
+            let interval = pollinf.interval;
+            future::loop_fn((interval, interval), move |(interval, delay)| {
                 // Make a copy of everything every time, because the loop function needs to be
-                // repeatable, i.e. we can't move anything out.
-                let pt = Self::poll_token(
+                // repeatable, i.e. we can't move anything out. The request is only built after
+                // sleeping, so that the expiry check is current.
+                let (secret, client, code, grant_type, pi, poll_fd) = (
                     application_secret.clone(),
                     client.clone(),
                     device_code.clone(),
+                    grant_type.clone(),
                     pollinf.clone(),
                     fd.clone(),
                 );
-                let maxn = wait.as_secs() / pollinf.interval.as_secs();
                 let mut fd = fd.clone();
-                let pollinf = pollinf.clone();
-                tokio_timer::sleep(pollinf.interval)
-                    .then(|_| pt)
-                    .then(move |r| match r {
-                        Ok(None) if i < maxn => match fd.pending(&pollinf) {
-                            Retry::Abort | Retry::Skip => {
-                                Box::new(Err(RequestError::Poll(PollError::TimedOut)).into_future())
+                let pollinf = PollInformation {
+                    interval,
+                    ..pollinf.clone()
+                };
+                tokio_timer::sleep(delay)
+                    .then(move |_| Self::poll_token(secret, client, code, grant_type, pi, poll_fd))
+                    .then(move |r| {
+                        match r {
+                            Ok(PollStatus::Pending) => match fd.pending(&pollinf) {
+                                Retry::Abort | Retry::Skip => {
+                                    Err(RequestError::Poll(PollError::TimedOut))
+                                }
+                                Retry::After(d) => {
+                                    Ok(future::Loop::Continue((interval, cmp::max(interval, d))))
+                                }
+                            },
+                            // The increased interval applies to all subsequent polls.
+                            Ok(PollStatus::SlowDown) => {
+                                let interval = interval + SLOW_DOWN_INCREMENT;
+                                Ok(future::Loop::Continue((interval, interval)))
                             }
-                            Retry::After(d) => Box::new(
-                                tokio_timer::sleep(d)
-                                    .then(move |_| Ok(future::Loop::Continue(i + 1))),
-                            )
-                                as Box<
-                                    dyn Future<
-                                            Item = future::Loop<Token, u64>,
-                                            Error = RequestError,
-                                        > + Send,
-                                >,
-                        },
-                        Ok(Some(tok)) => Box::new(Ok(future::Loop::Break(tok)).into_future()),
-                        Err(e @ PollError::AccessDenied)
-                        | Err(e @ PollError::TimedOut)
-                        | Err(e @ PollError::Expired(_)) => {
-                            Box::new(Err(RequestError::Poll(e)).into_future())
-                        }
-                        Err(ref e) if i < maxn => {
-                            error!("Unknown error from poll token api: {}", e);
-                            Box::new(Ok(future::Loop::Continue(i + 1)).into_future())
-                        }
-                        // Too many attempts.
-                        Ok(None) | Err(_) => {
-                            error!("Too many poll attempts");
-                            Box::new(Err(RequestError::Poll(PollError::TimedOut)).into_future())
+                            Ok(PollStatus::Done(tok)) => Ok(future::Loop::Break(tok)),
+                            // Connection errors are retried; polling stops once the device
+                            // code has expired.
+                            Err(ref e @ PollError::HttpError(_)) => {
+                                error!("Error polling the token endpoint: {}", e);
+                                Ok(future::Loop::Continue((interval, interval)))
+                            }
+                            // Only `authorization_pending` and `slow_down` continue polling
+                            // (RFC 8628, section 3.5).
+                            Err(e) => Err(RequestError::Poll(e)),
                         }
                     })
             })
@@ -250,6 +288,7 @@ where
         // https://github.com/rust-lang/rust/issues/22252
         let request = hyper::Request::post(device_code_url)
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
             .body(hyper::Body::from(req))
             .into_future();
         request
@@ -266,18 +305,17 @@ where
                             return Err(RequestError::ClientError(err));
                         }
                         Ok(res) => {
-                            // This return type is defined in https://tools.ietf.org/html/draft-ietf-oauth-device-flow-15#section-3.2
+                            // This return type is defined in https://tools.ietf.org/html/rfc8628#section-3.2
                             // The alias is present as Google use a non-standard name for verification_uri.
-                            // According to the standard interval is optional, however, all tested implementations provide it.
-                            // verification_uri_complete is optional in the standard but not provided in tested implementations.
                             #[derive(Deserialize)]
                             struct JsonData {
                                 device_code: String,
                                 user_code: String,
                                 #[serde(alias = "verification_url")]
                                 verification_uri: String,
+                                verification_uri_complete: Option<String>,
                                 expires_in: Option<i64>,
-                                interval: i64,
+                                interval: Option<i64>,
                             }
 
                             let json_str: String = res
@@ -293,15 +331,20 @@ where
                                 Ok(res) => return Err(RequestError::from(res)),
                             }
 
-                            let decoded: JsonData = json::from_str(&json_str).unwrap();
+                            let decoded: JsonData =
+                                json::from_str(&json_str).map_err(RequestError::JSONError)?;
 
                             let expires_in = decoded.expires_in.unwrap_or(60 * 60);
 
                             let pi = PollInformation {
                                 user_code: decoded.user_code,
                                 verification_url: decoded.verification_uri,
+                                verification_uri_complete: decoded.verification_uri_complete,
                                 expires_at: Utc::now() + chrono::Duration::seconds(expires_in),
-                                interval: Duration::from_secs(i64::abs(decoded.interval) as u64),
+                                interval: decoded
+                                    .interval
+                                    .map(|i| Duration::from_secs(i64::abs(i) as u64))
+                                    .unwrap_or(DEFAULT_POLL_INTERVAL),
                             };
                             Ok((pi, decoded.device_code))
                         }
@@ -310,31 +353,21 @@ where
             )
     }
 
-    /// If the first call is successful, this method may be called.
-    /// As long as we are waiting for authentication, it will return `Ok(None)`.
-    /// You should call it within the interval given the previously returned
-    /// `PollInformation.interval` field.
-    ///
-    /// The operation was successful once you receive an Ok(Some(Token)) for the first time.
-    /// Subsequent calls will return the previous result, which may also be an error state.
-    ///
-    /// Do not call after `PollError::Expired|PollError::AccessDenied` was among the
-    /// `Err(PollError)` variants as the flow will not do anything anymore.
-    /// Thus in any unsuccessful case which is not `PollError::HttpError`, you will have to start
-    /// over the entire flow, which requires a new instance of this type.
+    /// Poll the token endpoint once, as described in
+    /// [RFC 8628, section 3.4](https://tools.ietf.org/html/rfc8628#section-3.4).
     ///
-    /// > ⚠️ **Warning**: We assume the caller doesn't call faster than `interval` and are not
-    /// > protected against this kind of mis-use.
-    ///
-    /// # Examples
-    /// See test-cases in source code for a more complete example.
-    fn poll_token<'a>(
+    /// Returns `PollStatus::Pending` or `PollStatus::SlowDown` as long as the user hasn't
+    /// authorized the application yet, and the token once they have. Fails with
+    /// `PollError::Expired` once the device code has expired, and with
+    /// `PollError::AccessDenied` if the user declined; the flow has to start over in both cases.
+    fn poll_token(
         application_secret: ApplicationSecret,
         client: hyper::Client<C>,
         device_code: String,
+        grant_type: String,
         pi: PollInformation,
         mut fd: FD,
-    ) -> impl Future<Item = Option<Token>, Error = PollError> {
+    ) -> impl Future<Item = PollStatus, Error = PollError> {
         let expired = if pi.expires_at <= Utc::now() {
             fd.expired(&pi.expires_at);
             Err(PollError::Expired(pi.expires_at)).into_future()
@@ -343,58 +376,69 @@ where
         };
 
         // We should be ready for a new request
-        let req = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(&[
-                ("client_id", &application_secret.client_id[..]),
-                ("client_secret", &application_secret.client_secret),
-                ("code", &device_code),
-                ("grant_type", "http://oauth.net/grant_type/device/1.0"),
-            ])
+        let mut req = form_urlencoded::Serializer::new(String::new());
+        req.append_pair("client_id", &application_secret.client_id);
+        // Public clients, as usually used with this flow, have no secret.
+        if !application_secret.client_secret.is_empty() {
+            req.append_pair("client_secret", &application_secret.client_secret);
+        }
+        // The pre-standard grant named the device code parameter `code`.
+        let code_param = if grant_type == LEGACY_DEVICE_GRANT_TYPE {
+            "code"
+        } else {
+            "device_code"
+        };
+        let req = req
+            .append_pair(code_param, &device_code)
+            .append_pair("grant_type", &grant_type)
             .finish();
 
         let request = hyper::Request::post(&application_secret.token_uri)
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
             .body(hyper::Body::from(req))
             .unwrap(); // TODO: Error checking
         expired
             .and_then(move |_| client.request(request).map_err(|e| PollError::HttpError(e)))
-            .map(|res| {
+            .and_then(|res| {
                 res.into_body()
                     .concat2()
-                    .wait()
-                    .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                    .unwrap() // TODO: error handling
+                    .map_err(PollError::HttpError)
+                    .map(|c| String::from_utf8_lossy(&c).into_owned())
             })
             .and_then(move |json_str: String| {
                 #[derive(Deserialize)]
                 struct JsonError {
                     error: String,
+                    error_description: Option<String>,
                 }
 
-                match json::from_str::<JsonError>(&json_str) {
-                    Err(_) => {} // ignore, move on, it's not an error
-                    Ok(res) => {
-                        match res.error.as_ref() {
-                            "access_denied" => {
-                                fd.denied();
-                                return Err(PollError::AccessDenied);
-                            }
-                            "authorization_pending" => return Ok(None),
-                            s => {
-                                return Err(PollError::Other(format!(
-                                    "server message '{}' not understood",
-                                    s
-                                )))
-                            }
-                        };
-                    }
+                if let Ok(res) = json::from_str::<JsonError>(&json_str) {
+                    return match res.error.as_ref() {
+                        "authorization_pending" => Ok(PollStatus::Pending),
+                        "slow_down" => Ok(PollStatus::SlowDown),
+                        "access_denied" => {
+                            fd.denied();
+                            Err(PollError::AccessDenied)
+                        }
+                        "expired_token" => {
+                            fd.expired(&pi.expires_at);
+                            Err(PollError::Expired(pi.expires_at))
+                        }
+                        s => Err(PollError::Other(format!(
+                            "server message '{}' not understood: {}",
+                            s,
+                            res.error_description.unwrap_or_default()
+                        ))),
+                    };
                 }
 
-                // yes, we expect that !
-                let mut t: Token = json::from_str(&json_str).unwrap();
+                let mut t: Token = json::from_str(&json_str).map_err(|e| {
+                    PollError::Other(format!("invalid token response '{}': {}", json_str, e))
+                })?;
                 t.set_expiry_absolute();
 
-                Ok(Some(t.clone()))
+                Ok(PollStatus::Done(t))
             })
     }
 }
@@ -455,7 +499,7 @@ mod tests {
             let token_response = r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 1234567}"#;
             let _m = mockito::mock("POST", "/token")
                 .match_body(mockito::Matcher::Regex(
-                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&code=devicecode.*".to_string(),
+                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&device_code=devicecode.*".to_string(),
                 ))
                 .with_status(200)
                 .with_body(token_response)
@@ -486,7 +530,7 @@ mod tests {
             let token_response = r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 1234567}"#;
             let _m = mockito::mock("POST", "/token")
                 .match_body(mockito::Matcher::Regex(
-                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&code=devicecode.*".to_string(),
+                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&device_code=devicecode.*".to_string(),
                 ))
                 .with_status(200)
                 .with_body(token_response)
@@ -517,7 +561,7 @@ mod tests {
             let token_response = r#"{"error": "access_denied"}"#;
             let _m = mockito::mock("POST", "/token")
                 .match_body(mockito::Matcher::Regex(
-                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&code=devicecode.*".to_string(),
+                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&device_code=devicecode.*".to_string(),
                 ))
                 .with_status(400)
                 .with_body(token_response)
@@ -536,4 +580,102 @@ mod tests {
             _m.assert();
         }
     }
+
+    #[test]
+    fn test_device_slow_down_and_expiry() {
+        /// Polls again right away while the authorization is pending.
+        #[derive(Clone)]
+        struct FD;
+        impl FlowDelegate for FD {
+            fn present_user_code(&mut self, pi: &PollInformation) {
+                assert_eq!(
+                    Some("https://example.com/verify?code=usercode"),
+                    pi.verification_uri_complete.as_deref()
+                );
+            }
+            fn pending(&mut self, _: &PollInformation) -> Retry {
+                Retry::After(Duration::from_secs(0))
+            }
+        }
+
+        let server_url = mockito::server_url();
+        // A public client, which has no secret.
+        let app_secret = ApplicationSecret {
+            client_id: "client".to_string(),
+            token_uri: format!("{}/token", server_url),
+            ..Default::default()
+        };
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut flow = DeviceFlow::new(app_secret)
+            .delegate(FD)
+            .device_code_url(format!("{}/code", server_url))
+            .build_token_getter(client);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let token_body = "client_id=client&device_code=devicecode\
+                          &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code";
+
+        // The server tells us to slow down: Without the increased interval, we would poll
+        // continuously until the device code expires.
+        {
+            let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_uri": "https://example.com/verify", "verification_uri_complete": "https://example.com/verify?code=usercode", "expires_in": 2, "interval": 0}"#;
+            let _c = mockito::mock("POST", "/code")
+                .with_status(200)
+                .with_body(code_response)
+                .create();
+            let _t = mockito::mock("POST", "/token")
+                .match_body(token_body)
+                .with_status(400)
+                .with_body(r#"{"error": "slow_down"}"#)
+                .expect(1)
+                .create();
+            match rt.block_on(flow.token(vec!["scope"])) {
+                Err(RequestError::Poll(PollError::Expired(_))) => {}
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+            _t.assert();
+        }
+        // The server doesn't support the grant type: Fail right away instead of polling until
+        // the device code expires.
+        {
+            let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_uri": "https://example.com/verify", "verification_uri_complete": "https://example.com/verify?code=usercode", "expires_in": 1800}"#;
+            let _c = mockito::mock("POST", "/code")
+                .with_status(200)
+                .with_body(code_response)
+                .create();
+            let _t = mockito::mock("POST", "/token")
+                .match_body(token_body)
+                .with_status(400)
+                .with_body(r#"{"error": "unsupported_grant_type"}"#)
+                .expect(1)
+                .create();
+            match rt.block_on(flow.token(vec!["scope"])) {
+                Err(RequestError::Poll(PollError::Other(ref e))) => {
+                    assert!(e.contains("unsupported_grant_type"))
+                }
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+            _t.assert();
+        }
+        // The server reports the device code as expired.
+        {
+            let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_uri": "https://example.com/verify", "verification_uri_complete": "https://example.com/verify?code=usercode", "expires_in": 1800}"#;
+            let _c = mockito::mock("POST", "/code")
+                .with_status(200)
+                .with_body(code_response)
+                .create();
+            let _t = mockito::mock("POST", "/token")
+                .match_body(token_body)
+                .with_status(400)
+                .with_body(r#"{"error": "expired_token"}"#)
+                .expect(1)
+                .create();
+            match rt.block_on(flow.token(vec!["scope"])) {
+                Err(RequestError::Poll(PollError::Expired(_))) => {}
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+            _t.assert();
+        }
+    }
 }
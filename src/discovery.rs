@@ -0,0 +1,448 @@
+//! This module discovers the endpoints of an authorization server from the metadata it publishes
+//! ([RFC 8414](https://tools.ietf.org/html/rfc8414) and
+//! [OpenID Connect Discovery](https://openid.net/specs/openid-connect-discovery-1_0.html)), given
+//! only its issuer URL. The metadata is turned into a `Provider` for use with the flows.
+//!
+//! Fetched metadata is validated: The `issuer` it states has to be identical to the configured
+//! one, as otherwise a compromised or misconfigured server could redirect token requests to
+//! another party.
+
+use std::sync::{Arc, Mutex};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::provider::Provider;
+use crate::types::RequestError;
+
+use chrono::{DateTime, Utc};
+use futures::{future, prelude::*};
+use hyper::header;
+use url::Url;
+
+/// A metadata document published below an issuer URL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WellKnown {
+    /// `/.well-known/openid-configuration`, appended to the issuer URL (OpenID Connect
+    /// Discovery).
+    OpenIdConfiguration,
+    /// `/.well-known/oauth-authorization-server`, inserted between the host and the path of the
+    /// issuer URL (RFC 8414).
+    OAuthAuthorizationServer,
+}
+
+/// The authorization server metadata, as far as this crate uses it. Endpoints the server doesn't
+/// publish are `None`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerMetadata {
+    /// The issuer identifier, identical to the issuer URL the metadata was discovered from.
+    pub issuer: String,
+    /// The authorization endpoint.
+    pub authorization_endpoint: Option<String>,
+    /// The token endpoint.
+    pub token_endpoint: Option<String>,
+    /// The device authorization endpoint (RFC 8628).
+    pub device_authorization_endpoint: Option<String>,
+    /// The token revocation endpoint (RFC 7009).
+    pub revocation_endpoint: Option<String>,
+    /// The token introspection endpoint (RFC 7662).
+    pub introspection_endpoint: Option<String>,
+    /// The OpenID Connect UserInfo endpoint.
+    pub userinfo_endpoint: Option<String>,
+    /// The URL of the server's JWKS.
+    pub jwks_uri: Option<String>,
+    /// The scopes the server supports.
+    pub scopes_supported: Option<Vec<String>>,
+    /// The grant types the server supports.
+    pub grant_types_supported: Option<Vec<String>>,
+    /// The PKCE code challenge methods the server supports.
+    pub code_challenge_methods_supported: Option<Vec<String>>,
+}
+
+impl ServerMetadata {
+    /// Check that the metadata was published by `issuer` and contains usable endpoints.
+    fn validate(&self, issuer: &str) -> Result<(), RequestError> {
+        if self.issuer != issuer {
+            return Err(RequestError::BadServerResponse(format!(
+                "metadata of issuer '{}' states issuer '{}'",
+                issuer, self.issuer
+            )));
+        }
+        // All flows of this crate need the token endpoint.
+        if self.token_endpoint.is_none() {
+            return Err(RequestError::BadServerResponse(format!(
+                "metadata of issuer '{}' has no token_endpoint",
+                issuer
+            )));
+        }
+        let endpoints = [
+            &self.authorization_endpoint,
+            &self.token_endpoint,
+            &self.device_authorization_endpoint,
+            &self.revocation_endpoint,
+            &self.introspection_endpoint,
+            &self.userinfo_endpoint,
+            &self.jwks_uri,
+        ];
+        for endpoint in endpoints.iter().filter_map(|e| e.as_ref()) {
+            if let Err(e) = Url::parse(endpoint) {
+                return Err(RequestError::BadServerResponse(format!(
+                    "metadata of issuer '{}' has invalid endpoint '{}': {}",
+                    issuer, endpoint, e
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `Provider` described by the metadata. Endpoints the server doesn't publish are `None`,
+    /// except for the token endpoint, which `Discovery` requires; it is empty if missing. PKCE is
+    /// used unless the server lists the code challenge methods it supports, and `S256` is not
+    /// among them.
+    pub fn provider(&self) -> Provider {
+        let pkce = match self.code_challenge_methods_supported {
+            Some(ref methods) => methods.iter().any(|m| m == "S256"),
+            None => true,
+        };
+        Provider {
+            device_authorization_uri: self.device_authorization_endpoint.clone(),
+            revocation_uri: self.revocation_endpoint.clone(),
+            introspection_uri: self.introspection_endpoint.clone(),
+            userinfo_uri: self.userinfo_endpoint.clone(),
+            jwks_uri: self.jwks_uri.clone(),
+            pkce,
+            auth_uri: self.authorization_endpoint.clone(),
+            ..Provider::new(
+                String::new(),
+                self.token_endpoint.clone().unwrap_or_default(),
+            )
+        }
+    }
+}
+
+/// The URL of the `document` published by `issuer`.
+fn well_known_url(issuer: &str, document: WellKnown) -> Result<String, RequestError> {
+    let mut url = Url::parse(issuer)
+        .map_err(|e| RequestError::UserError(format!("bad issuer URL '{}': {}", issuer, e)))?;
+    if url.cannot_be_a_base() || url.query().is_some() || url.fragment().is_some() {
+        return Err(RequestError::UserError(format!(
+            "issuer URL '{}' must not have a query or fragment",
+            issuer
+        )));
+    }
+    let path = url.path().trim_end_matches('/').to_string();
+    match document {
+        WellKnown::OpenIdConfiguration => {
+            url.set_path(&format!("{}/.well-known/openid-configuration", path))
+        }
+        WellKnown::OAuthAuthorizationServer => {
+            url.set_path(&format!("/.well-known/oauth-authorization-server{}", path))
+        }
+    }
+    Ok(url.into_string())
+}
+
+/// Fetch the `document` published by `issuer`. Returns `None` if the server doesn't publish it.
+fn fetch_metadata<C: 'static + hyper::client::connect::Connect>(
+    client: hyper::Client<C>,
+    issuer: String,
+    document: WellKnown,
+) -> impl Future<Item = Option<ServerMetadata>, Error = RequestError> {
+    well_known_url(&issuer, document)
+        .and_then(|url| {
+            hyper::Request::get(url)
+                .header(header::ACCEPT, "application/json")
+                .body(hyper::Body::empty())
+                .map_err(|e| RequestError::UserError(format!("bad issuer URL: {}", e)))
+        })
+        .into_future()
+        .and_then(move |request| client.request(request).map_err(RequestError::ClientError))
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map_err(RequestError::ClientError)
+                .map(move |body| (status, body))
+        })
+        .and_then(move |(status, body)| {
+            if status == hyper::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !status.is_success() {
+                return Err(RequestError::BadServerResponse(format!(
+                    "fetching the metadata of '{}' failed with status {}: {}",
+                    issuer,
+                    status,
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+            let metadata: ServerMetadata =
+                serde_json::from_slice(&body).map_err(RequestError::JSONError)?;
+            metadata.validate(&issuer)?;
+            Ok(Some(metadata))
+        })
+}
+
+/// Configures the discovery of an authorization server's metadata.
+///
+/// ```no_run
+/// use futures::prelude::*;
+/// use yup_oauth2::{DeviceFlow, Discovery};
+///
+/// let discovery = Discovery::new("https://sso.example.com/realms/internal").build();
+/// let fut = discovery
+///     .provider()
+///     .map(|provider| {
+///         let secret = provider.application_secret("cli".to_string(), String::new());
+///         let flow = DeviceFlow::new(secret).provider(provider);
+///         // Use the flow with an Authenticator.
+///     })
+///     .map_err(|e| println!("error: {:?}", e));
+/// tokio::run(fut);
+/// ```
+pub struct Discovery<C> {
+    client: C,
+    issuer: String,
+    documents: Vec<WellKnown>,
+    cache_duration: chrono::Duration,
+}
+
+impl Discovery<DefaultHyperClient> {
+    /// Discover the metadata of the authorization server identified by `issuer`. By default,
+    /// `WellKnown::OpenIdConfiguration` is fetched, and `WellKnown::OAuthAuthorizationServer` if
+    /// the server doesn't publish the former.
+    pub fn new<S: Into<String>>(issuer: S) -> Self {
+        Discovery {
+            client: DefaultHyperClient,
+            issuer: issuer.into(),
+            documents: vec![
+                WellKnown::OpenIdConfiguration,
+                WellKnown::OAuthAuthorizationServer,
+            ],
+            cache_duration: chrono::Duration::hours(24),
+        }
+    }
+}
+
+impl<C> Discovery<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(self, hyper_client: NewC) -> Discovery<NewC> {
+        Discovery {
+            client: hyper_client,
+            issuer: self.issuer,
+            documents: self.documents,
+            cache_duration: self.cache_duration,
+        }
+    }
+
+    /// Only fetch the given metadata document.
+    pub fn well_known(self, document: WellKnown) -> Self {
+        Discovery {
+            documents: vec![document],
+            ..self
+        }
+    }
+
+    /// How long to use fetched metadata before fetching it again. The default is 24 hours.
+    pub fn cache_duration(self, duration: chrono::Duration) -> Self {
+        Discovery {
+            cache_duration: duration,
+            ..self
+        }
+    }
+
+    /// Build a client discovering the metadata as configured.
+    pub fn build(self) -> DiscoveryClient<C::Connector> {
+        DiscoveryClient {
+            client: self.client.build_hyper_client(),
+            issuer: self.issuer,
+            documents: self.documents,
+            cache_duration: self.cache_duration,
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Discovers an authorization server's metadata as configured by `Discovery`. The metadata is
+/// cached, and shared by all clones of the client.
+pub struct DiscoveryClient<C> {
+    client: hyper::Client<C, hyper::Body>,
+    issuer: String,
+    documents: Vec<WellKnown>,
+    cache_duration: chrono::Duration,
+    cache: Arc<Mutex<Option<(ServerMetadata, DateTime<Utc>)>>>,
+}
+
+impl<C> Clone for DiscoveryClient<C> {
+    fn clone(&self) -> Self {
+        DiscoveryClient {
+            client: self.client.clone(),
+            issuer: self.issuer.clone(),
+            documents: self.documents.clone(),
+            cache_duration: self.cache_duration,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<C: 'static + hyper::client::connect::Connect> DiscoveryClient<C> {
+    /// The issuer's metadata, fetched unless a cached copy is still fresh.
+    pub fn metadata(&self) -> Box<dyn Future<Item = ServerMetadata, Error = RequestError> + Send> {
+        if let Some((ref metadata, fetched_at)) = *self.cache.lock().unwrap() {
+            if Utc::now() < fetched_at + self.cache_duration {
+                return Box::new(future::ok(metadata.clone()));
+            }
+        }
+
+        let client = self.client.clone();
+        let issuer = self.issuer.clone();
+        let cache = self.cache.clone();
+        Box::new(
+            future::loop_fn(self.documents.clone(), move |mut documents| {
+                let document = documents.remove(0);
+                fetch_metadata(client.clone(), issuer.clone(), document).and_then(move |metadata| {
+                    match metadata {
+                        Some(metadata) => Ok(future::Loop::Break(metadata)),
+                        None if !documents.is_empty() => Ok(future::Loop::Continue(documents)),
+                        None => Err(RequestError::BadServerResponse(format!(
+                            "the issuer doesn't publish {:?} metadata",
+                            document
+                        ))),
+                    }
+                })
+            })
+            .map(move |metadata| {
+                *cache.lock().unwrap() = Some((metadata.clone(), Utc::now()));
+                metadata
+            }),
+        )
+    }
+
+    /// The `Provider` described by the issuer's metadata.
+    pub fn provider(&self) -> Box<dyn Future<Item = Provider, Error = RequestError> + Send> {
+        Box::new(self.metadata().map(|metadata| metadata.provider()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper_rustls::HttpsConnector;
+
+    #[test]
+    fn test_well_known_url() {
+        assert_eq!(
+            well_known_url("https://sso.example.com", WellKnown::OpenIdConfiguration).unwrap(),
+            "https://sso.example.com/.well-known/openid-configuration"
+        );
+        assert_eq!(
+            well_known_url(
+                "https://sso.example.com/realms/internal/",
+                WellKnown::OpenIdConfiguration
+            )
+            .unwrap(),
+            "https://sso.example.com/realms/internal/.well-known/openid-configuration"
+        );
+        assert_eq!(
+            well_known_url(
+                "https://sso.example.com",
+                WellKnown::OAuthAuthorizationServer
+            )
+            .unwrap(),
+            "https://sso.example.com/.well-known/oauth-authorization-server"
+        );
+        assert_eq!(
+            well_known_url(
+                "https://sso.example.com/tenant",
+                WellKnown::OAuthAuthorizationServer
+            )
+            .unwrap(),
+            "https://sso.example.com/.well-known/oauth-authorization-server/tenant"
+        );
+        assert!(well_known_url(
+            "https://sso.example.com/?tenant=1",
+            WellKnown::OpenIdConfiguration
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_discovery_end2end() {
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let issuer = format!("{}/tenant", mockito::server_url());
+        let metadata = format!(
+            r#"{{"issuer": "{0}", "authorization_endpoint": "{0}/authorize", "token_endpoint": "{0}/token", "device_authorization_endpoint": "{0}/device", "introspection_endpoint": "{0}/introspect", "userinfo_endpoint": "{0}/userinfo", "jwks_uri": "{0}/keys", "response_types_supported": ["code"], "code_challenge_methods_supported": ["plain", "S256"]}}"#,
+            issuer
+        );
+
+        // The server only publishes RFC 8414 metadata, which is cached.
+        {
+            let _oidc = mockito::mock("GET", "/tenant/.well-known/openid-configuration")
+                .with_status(404)
+                .expect(1)
+                .create();
+            let _oauth = mockito::mock("GET", "/.well-known/oauth-authorization-server/tenant")
+                .with_status(200)
+                .with_body(&metadata)
+                .expect(1)
+                .create();
+            let discovery = Discovery::new(issuer.clone())
+                .hyper_client(client.clone())
+                .build();
+            let provider = rt.block_on(discovery.provider()).unwrap();
+            assert_eq!(provider.auth_uri, Some(format!("{}/authorize", issuer)));
+            assert_eq!(provider.token_uri, format!("{}/token", issuer));
+            assert_eq!(
+                provider.device_authorization_uri,
+                Some(format!("{}/device", issuer))
+            );
+            assert_eq!(provider.revocation_uri, None);
+            assert_eq!(
+                provider.introspection_uri,
+                Some(format!("{}/introspect", issuer))
+            );
+            assert_eq!(provider.userinfo_uri, Some(format!("{}/userinfo", issuer)));
+            assert_eq!(provider.jwks_uri, Some(format!("{}/keys", issuer)));
+            assert!(provider.pkce);
+            let metadata = rt.block_on(discovery.clone().metadata()).unwrap();
+            assert_eq!(metadata.issuer, issuer);
+            _oidc.assert();
+            _oauth.assert();
+        }
+        // The metadata states another issuer.
+        {
+            let _oidc = mockito::mock("GET", "/tenant/.well-known/openid-configuration")
+                .with_status(200)
+                .with_body(&metadata.replace("/tenant\"", "/other\""))
+                .create();
+            let discovery = Discovery::new(issuer.clone())
+                .hyper_client(client.clone())
+                .build();
+            match rt.block_on(discovery.metadata()) {
+                Err(RequestError::BadServerResponse(_)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        // The server publishes no metadata.
+        {
+            let _oauth = mockito::mock("GET", "/.well-known/oauth-authorization-server/tenant")
+                .with_status(404)
+                .create();
+            let discovery = Discovery::new(issuer)
+                .hyper_client(client)
+                .well_known(WellKnown::OAuthAuthorizationServer)
+                .build();
+            match rt.block_on(discovery.metadata()) {
+                Err(RequestError::BadServerResponse(_)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+}
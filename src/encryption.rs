@@ -0,0 +1,237 @@
+//! Encryption of token files at rest, see `DiskTokenStorage::new_encrypted()`.
+//!
+//! Token files are encrypted with AES-256-GCM. The encrypted file is a JSON envelope
+//! `{"key_id": ..., "nonce": ..., "ciphertext": ...}`; the key ID tells which key was used, so
+//! that files written with an older key can still be read after rotating to a new one.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::num::NonZeroU32;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Number of PBKDF2 iterations used by `EncryptionKeys::from_passphrase()`.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// The keys used to encrypt and decrypt token files. New files are always encrypted with the
+/// current key; old keys are only used for decrypting files written before a key rotation.
+///
+/// ```
+/// use yup_oauth2::EncryptionKeys;
+///
+/// let keys = EncryptionKeys::from_passphrase("2019-10", "new passphrase", b"my-app")
+///     .old_key_from_passphrase("2019-01", "old passphrase", b"my-app");
+/// ```
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    current: (String, [u8; 32]),
+    old: Vec<(String, [u8; 32])>,
+}
+
+impl EncryptionKeys {
+    /// Use the provided 256 bit key, identified by `key_id`, for encryption.
+    pub fn new<S: Into<String>>(key_id: S, key: [u8; 32]) -> Self {
+        EncryptionKeys {
+            current: (key_id.into(), key),
+            old: Vec::new(),
+        }
+    }
+
+    /// Derive the key from a passphrase (e.g. read from an environment variable) using
+    /// PBKDF2-HMAC-SHA256. The salt should be unique to your application.
+    pub fn from_passphrase<S: Into<String>>(key_id: S, passphrase: &str, salt: &[u8]) -> Self {
+        Self::new(key_id, derive_key(passphrase, salt))
+    }
+
+    /// Additionally accept files encrypted with an older key.
+    pub fn old_key<S: Into<String>>(mut self, key_id: S, key: [u8; 32]) -> Self {
+        self.old.push((key_id.into(), key));
+        self
+    }
+
+    /// Like `old_key()`, deriving the key from a passphrase.
+    pub fn old_key_from_passphrase<S: Into<String>>(
+        self,
+        key_id: S,
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Self {
+        self.old_key(key_id, derive_key(passphrase, salt))
+    }
+
+    fn find(&self, key_id: &str) -> Option<&[u8; 32]> {
+        std::iter::once(&self.current)
+            .chain(self.old.iter())
+            .find(|(id, _)| id == key_id)
+            .map(|(_, key)| key)
+    }
+}
+
+impl fmt::Debug for EncryptionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the keys themselves.
+        f.debug_struct("EncryptionKeys")
+            .field("current", &self.current.0)
+            .field(
+                "old",
+                &self.old.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+/// Returned (wrapped in an `io::Error` of kind `InvalidData`) if a token file can not be
+/// decrypted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecryptionError {
+    /// The file is not an encrypted token file.
+    NotEncrypted,
+    /// The file was encrypted with a key that is not known.
+    UnknownKey(String),
+    /// Decryption failed: the key is wrong or the file was tampered with.
+    Failed(String),
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecryptionError::NotEncrypted => "token file is not encrypted".fmt(f),
+            DecryptionError::UnknownKey(ref id) => write!(
+                f,
+                "token file is encrypted with unknown key '{}'; was it removed after a rotation?",
+                id
+            ),
+            DecryptionError::Failed(ref id) => write!(
+                f,
+                "could not decrypt token file with key '{}': wrong key or corrupted file",
+                id
+            ),
+        }
+    }
+}
+
+impl Error for DecryptionError {}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    key_id: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn aead_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap())
+}
+
+/// Encrypt `plaintext` with the current key, returning the serialized envelope.
+pub(crate) fn encrypt(keys: &EncryptionKeys, plaintext: &str) -> io::Result<String> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not generate a nonce"))?;
+
+    let (ref key_id, ref key) = keys.current;
+    let mut in_out = plaintext.as_bytes().to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(key_id.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not encrypt token file"))?;
+
+    let envelope = Envelope {
+        key_id: key_id.clone(),
+        nonce: base64::encode_config(&nonce, base64::URL_SAFE_NO_PAD),
+        ciphertext: base64::encode_config(&in_out, base64::URL_SAFE_NO_PAD),
+    };
+    serde_json::to_string(&envelope).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Decrypt a serialized envelope using the key it names.
+pub(crate) fn decrypt(keys: &EncryptionKeys, contents: &str) -> io::Result<String> {
+    let invalid = |e: DecryptionError| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let envelope: Envelope =
+        serde_json::from_str(contents).map_err(|_| invalid(DecryptionError::NotEncrypted))?;
+    let key = keys
+        .find(&envelope.key_id)
+        .ok_or_else(|| invalid(DecryptionError::UnknownKey(envelope.key_id.clone())))?;
+    let failed = || invalid(DecryptionError::Failed(envelope.key_id.clone()));
+
+    let nonce =
+        base64::decode_config(&envelope.nonce, base64::URL_SAFE_NO_PAD).map_err(|_| failed())?;
+    let nonce = Nonce::try_assume_unique_for_key(&nonce).map_err(|_| failed())?;
+    let mut in_out = base64::decode_config(&envelope.ciphertext, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| failed())?;
+    let plaintext = aead_key(key)
+        .open_in_place(nonce, Aad::from(envelope.key_id.as_bytes()), &mut in_out)
+        .map_err(|_| failed())?;
+    String::from_utf8(plaintext.to_vec()).map_err(|_| failed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decryption_error(e: io::Error) -> DecryptionError {
+        e.into_inner()
+            .unwrap()
+            .downcast_ref::<DecryptionError>()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let old = EncryptionKeys::new("old", [1; 32]);
+        let rotated = EncryptionKeys::new("new", [2; 32]).old_key("old", [1; 32]);
+
+        let encrypted = encrypt(&old, "secret tokens").unwrap();
+        assert!(!encrypted.contains("secret"));
+        assert_eq!(decrypt(&old, &encrypted).unwrap(), "secret tokens");
+        // Files written with the old key can still be read after a rotation...
+        assert_eq!(decrypt(&rotated, &encrypted).unwrap(), "secret tokens");
+        // ...but new files are written with the new key.
+        let reencrypted = encrypt(&rotated, "secret tokens").unwrap();
+        assert_eq!(
+            decryption_error(decrypt(&old, &reencrypted).unwrap_err()),
+            DecryptionError::UnknownKey("new".to_string())
+        );
+
+        let wrong_key = EncryptionKeys::new("old", [3; 32]);
+        assert_eq!(
+            decryption_error(decrypt(&wrong_key, &encrypted).unwrap_err()),
+            DecryptionError::Failed("old".to_string())
+        );
+        assert_eq!(
+            decryption_error(decrypt(&old, r#"{"tokens": []}"#).unwrap_err()),
+            DecryptionError::NotEncrypted
+        );
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let a = EncryptionKeys::from_passphrase("k", "passphrase", b"salt");
+        let b = EncryptionKeys::from_passphrase("k", "passphrase", b"salt");
+        let c = EncryptionKeys::from_passphrase("k", "other passphrase", b"salt");
+        let encrypted = encrypt(&a, "tokens").unwrap();
+        assert_eq!(decrypt(&b, &encrypted).unwrap(), "tokens");
+        assert!(decrypt(&c, &encrypted).is_err());
+    }
+}
@@ -0,0 +1,779 @@
+//! This module provides a token source (`GetToken`) for Workload Identity Federation, i.e.
+//! credential files of type `external_account`. Instead of a private key, such a file describes
+//! where to find a token issued by a third-party identity provider (AWS, Azure, an OIDC or SAML
+//! provider, ...): a file, a URL or an executable. That subject token is exchanged for a Google
+//! access token at the Security Token Service using the OAuth 2.0 token exchange grant
+//! ([RFC 8693](https://tools.ietf.org/html/rfc8693)); optionally, the result is used to
+//! impersonate a service account.
+//!
+//! Resources:
+//! - [Workload Identity Federation](https://cloud.google.com/iam/docs/workload-identity-federation)
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::impersonated::{generate_access_token, GenerateAccessTokenRequest};
+use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
+use crate::token_exchange::{TokenExchange, TokenExchangeClient, ACCESS_TOKEN_TYPE};
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+use ::log::{error, log};
+use futures::sync::oneshot;
+use futures::{future, prelude::*};
+use tokio_timer::Timeout;
+
+/// Google's Security Token Service endpoint.
+pub const GOOGLE_STS_URL: &str = "https://sts.googleapis.com/v1/token";
+
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Executables are only run if this environment variable is set to `1`.
+const ALLOW_EXECUTABLES_ENV_VAR: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+const DEFAULT_EXECUTABLE_TIMEOUT_MILLIS: u64 = 30_000;
+
+/// JSON schema of an external account credentials file, as generated by
+/// `gcloud iam workload-identity-pools create-cred-config`.
+///
+/// Use `external_account_secret_from_file()` to read one from disk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountSecret {
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    /// The workload identity pool provider, sent as `audience` of the token exchange.
+    pub audience: String,
+    /// The type of the subject token, e.g. `urn:ietf:params:oauth:token-type:jwt`.
+    pub subject_token_type: String,
+    /// The Security Token Service endpoint; defaults to `GOOGLE_STS_URL`.
+    pub token_url: Option<String>,
+    /// The `generateAccessToken` URL of the service account to impersonate, if any.
+    pub service_account_impersonation_url: Option<String>,
+    pub service_account_impersonation: Option<ServiceAccountImpersonation>,
+    /// Where to find the subject token.
+    pub credential_source: CredentialSource,
+    /// The project to bill API requests to. Send it as the `x-goog-user-project` header.
+    pub quota_project_id: Option<String>,
+    /// The client credentials to authenticate the token exchange with, if required.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Options for impersonating the service account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceAccountImpersonation {
+    /// The lifetime of impersonated tokens; the default is one hour.
+    pub token_lifetime_seconds: Option<i64>,
+}
+
+/// The source of the subject token. Exactly one of `file`, `url` and `executable` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialSource {
+    /// A file containing the subject token.
+    pub file: Option<String>,
+    /// A URL the subject token is fetched from with a GET request.
+    pub url: Option<String>,
+    /// Headers sent along when fetching `url`.
+    pub headers: Option<HashMap<String, String>>,
+    /// An executable printing the subject token.
+    pub executable: Option<ExecutableSource>,
+    /// How to extract the subject token from the contents of the file or the URL's response.
+    pub format: Option<CredentialSourceFormat>,
+}
+
+/// An executable printing the subject token, see
+/// [Google's documentation](https://cloud.google.com/iam/docs/using-workload-identity-federation#oidc-executable).
+/// Executables are only run if the environment variable `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES`
+/// is set to `1`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecutableSource {
+    /// The command line; arguments are separated by whitespace. Quotes and backslashes work like
+    /// in a POSIX shell, but no other shell syntax is supported.
+    pub command: String,
+    /// How long the executable may run; the default is 30 seconds.
+    pub timeout_millis: Option<u64>,
+    /// A file the executable caches its response in. It is read before running the executable.
+    pub output_file: Option<String>,
+}
+
+/// The format of a file or URL subject token source.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialSourceFormat {
+    /// `text` (the default) or `json`.
+    #[serde(rename = "type")]
+    pub format_type: String,
+    /// For `json`, the field containing the subject token.
+    pub subject_token_field_name: Option<String>,
+}
+
+/// Extract the subject token from `contents`.
+fn parse_subject_token(
+    format: &Option<CredentialSourceFormat>,
+    contents: &str,
+) -> Result<String, RequestError> {
+    match *format {
+        Some(ref format) if format.format_type == "json" => {
+            let field = format.subject_token_field_name.as_ref().ok_or_else(|| {
+                RequestError::UserError(
+                    "credential_source.format lacks subject_token_field_name".to_string(),
+                )
+            })?;
+            let json: serde_json::Value =
+                serde_json::from_str(contents).map_err(RequestError::JSONError)?;
+            match json.get(field).and_then(|v| v.as_str()) {
+                Some(token) => Ok(token.to_string()),
+                None => Err(RequestError::BadServerResponse(format!(
+                    "subject token field '{}' not found",
+                    field
+                ))),
+            }
+        }
+        Some(ref format) if format.format_type != "text" => Err(RequestError::UserError(format!(
+            "unsupported credential_source format '{}'",
+            format.format_type
+        ))),
+        _ => Ok(contents.trim().to_string()),
+    }
+}
+
+/// The response of a subject token executable.
+#[derive(Deserialize, Debug)]
+struct ExecutableResponse {
+    success: bool,
+    id_token: Option<String>,
+    saml_response: Option<String>,
+    expiration_time: Option<i64>,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+impl ExecutableResponse {
+    /// The subject token, or `None` if the response has expired.
+    fn subject_token(self) -> Result<Option<String>, RequestError> {
+        if !self.success {
+            return Err(RequestError::NegativeServerResponse(
+                self.code.unwrap_or_else(|| "executable failed".to_string()),
+                self.message,
+            ));
+        }
+        if let Some(expiration_time) = self.expiration_time {
+            if expiration_time <= chrono::Utc::now().timestamp() {
+                return Ok(None);
+            }
+        }
+        match self.id_token.or(self.saml_response) {
+            Some(token) => Ok(Some(token)),
+            None => Err(RequestError::BadServerResponse(
+                "executable response lacks a token".to_string(),
+            )),
+        }
+    }
+}
+
+/// Whether running subject token executables has been allowed by setting
+/// `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES=1`.
+fn executables_allowed() -> bool {
+    env::var(ALLOW_EXECUTABLES_ENV_VAR).ok().as_deref() == Some("1")
+}
+
+/// Splits a command line into the program and its arguments. Like in a POSIX shell, quotes
+/// group words and a backslash escapes the next character outside of single quotes.
+fn split_command(command: &str) -> Result<Vec<String>, RequestError> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => {
+                    return Err(RequestError::UserError(
+                        "executable command ends with a backslash".to_string(),
+                    ))
+                }
+            },
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(RequestError::UserError(
+            "unterminated quote in executable command".to_string(),
+        ));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Starts the subject token executable with its output and errors piped.
+fn spawn_executable(
+    executable: &ExecutableSource,
+    secret: &ExternalAccountSecret,
+) -> Result<Child, RequestError> {
+    let args = split_command(&executable.command)?;
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| RequestError::UserError("empty executable command".to_string()))?;
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", &secret.audience)
+        .env(
+            "GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE",
+            &secret.subject_token_type,
+        )
+        .env("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(ref output_file) = executable.output_file {
+        command.env("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", output_file);
+    }
+    if let Some(ref url) = secret.service_account_impersonation_url {
+        if let Some(email) = impersonated_email(url) {
+            command.env("GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL", email);
+        }
+    }
+    command.spawn().map_err(RequestError::LowLevelError)
+}
+
+/// Collects the output of `child` and waits for it to exit, on a thread of its own so that the
+/// executor isn't blocked.
+fn wait_for_output(child: Child) -> oneshot::Receiver<io::Result<Output>> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(child.wait_with_output());
+    });
+    rx
+}
+
+/// Kills the process `pid`, e.g. a timed out executable still owned by its `wait_for_output`
+/// thread.
+#[cfg(unix)]
+fn kill_process(pid: u32) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process(pid: u32) -> io::Result<()> {
+    Command::new("taskkill")
+        .args(&["/F", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|_| ())
+}
+
+/// Run the subject token executable (or use its cached response), unless executables are not
+/// `allowed`.
+fn run_executable(
+    executable: &ExecutableSource,
+    secret: &ExternalAccountSecret,
+    allowed: bool,
+) -> Box<dyn Future<Item = String, Error = RequestError> + Send> {
+    if !allowed {
+        return Box::new(future::err(RequestError::UserError(format!(
+            "executable credential sources are disabled; set {}=1 to allow them",
+            ALLOW_EXECUTABLES_ENV_VAR
+        ))));
+    }
+    if let Some(ref output_file) = executable.output_file {
+        if let Ok(contents) = fs::read_to_string(output_file) {
+            if let Ok(response) = serde_json::from_str::<ExecutableResponse>(&contents) {
+                match response.subject_token() {
+                    Ok(Some(token)) => return Box::new(future::ok(token)),
+                    Ok(None) => {}
+                    Err(e) => return Box::new(future::err(e)),
+                }
+            }
+        }
+    }
+
+    let child = match spawn_executable(executable, secret) {
+        Ok(child) => child,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let pid = child.id();
+    let timeout = Duration::from_millis(
+        executable
+            .timeout_millis
+            .unwrap_or(DEFAULT_EXECUTABLE_TIMEOUT_MILLIS),
+    );
+    let output = wait_for_output(child);
+    Box::new(Timeout::new(output, timeout).then(move |r| {
+        let output = match r {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return Err(RequestError::LowLevelError(e)),
+            Err(ref e) if e.is_elapsed() => {
+                if let Err(e) = kill_process(pid) {
+                    error!("Failed to kill the executable (pid {}): {}", pid, e);
+                }
+                return Err(RequestError::LowLevelError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the executable timed out",
+                )));
+            }
+            Err(e) => {
+                return Err(RequestError::LowLevelError(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("waiting for the executable failed: {}", e),
+                )))
+            }
+        };
+        if !output.status.success() {
+            return Err(RequestError::BadServerResponse(format!(
+                "the executable failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let response: ExecutableResponse =
+            serde_json::from_slice(&output.stdout).map_err(RequestError::JSONError)?;
+        response.subject_token()?.ok_or_else(|| {
+            RequestError::BadServerResponse("the executable returned an expired token".to_string())
+        })
+    }))
+}
+
+/// Extracts the service account email from a `generateAccessToken` URL.
+fn impersonated_email(url: &str) -> Option<&str> {
+    let start = url.rfind("/serviceAccounts/")? + "/serviceAccounts/".len();
+    let end = url.rfind(":generateAccessToken")?;
+    url.get(start..end)
+}
+
+/// A token source (`GetToken`) yielding Google access tokens for external account credentials.
+/// Like `ServiceAccountAccess`, it caches tokens and requests new ones once they have expired, so
+/// there is no need to wrap it in an `Authenticator`.
+pub struct ExternalAccountAccess<C> {
+    client: C,
+    secret: ExternalAccountSecret,
+}
+
+impl ExternalAccountAccess<DefaultHyperClient> {
+    /// Create a new ExternalAccountAccess with the provided credentials.
+    pub fn new(secret: ExternalAccountSecret) -> Self {
+        ExternalAccountAccess {
+            client: DefaultHyperClient,
+            secret,
+        }
+    }
+}
+
+impl<C> ExternalAccountAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ExternalAccountAccess<NewC> {
+        ExternalAccountAccess {
+            client: hyper_client,
+            secret: self.secret,
+        }
+    }
+
+    /// The quota project named in the credentials, if any. The built token source doesn't
+    /// keep it; `ApplicationDefaultCredentials` passes it on with
+    /// `BoxedTokenSource::quota_project_id()`.
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.secret.quota_project_id.as_deref()
+    }
+
+    /// Build the configured ExternalAccountAccess.
+    pub fn build(self) -> impl GetToken {
+        let client = self.client.build_hyper_client();
+        let mut exchange = TokenExchange::new(
+            self.secret
+                .token_url
+                .clone()
+                .unwrap_or_else(|| GOOGLE_STS_URL.to_string()),
+        )
+        .hyper_client(client.clone())
+        .audience(self.secret.audience.clone())
+        .requested_token_type(ACCESS_TOKEN_TYPE.to_string());
+        if let (Some(id), Some(secret)) = (&self.secret.client_id, &self.secret.client_secret) {
+            exchange = exchange.client_credentials(id.clone(), secret.clone());
+        }
+        ExternalAccountAccessImpl {
+            client,
+            exchange: exchange.build(),
+            secret: self.secret,
+            cache: Arc::new(Mutex::new(MemoryStorage::default())),
+        }
+    }
+}
+
+struct ExternalAccountAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    exchange: TokenExchangeClient<C>,
+    secret: ExternalAccountSecret,
+    cache: Arc<Mutex<MemoryStorage>>,
+}
+
+impl<C: 'static + hyper::client::connect::Connect> ExternalAccountAccessImpl<C> {
+    /// Obtain the subject token from the credential source.
+    fn subject_token(&self) -> Box<dyn Future<Item = String, Error = RequestError> + Send> {
+        let source = &self.secret.credential_source;
+        if let Some(ref path) = source.file {
+            let (path, format) = (path.clone(), source.format.clone());
+            Box::new(futures::lazy(move || {
+                let contents = fs::read_to_string(&path).map_err(RequestError::LowLevelError)?;
+                parse_subject_token(&format, &contents)
+            }))
+        } else if let Some(ref url) = source.url {
+            let mut request = hyper::Request::get(url.as_str());
+            for (name, value) in source.headers.iter().flatten() {
+                request.header(name.as_str(), value.as_str());
+            }
+            let request = match request.body(hyper::Body::empty()) {
+                Ok(request) => request,
+                Err(e) => {
+                    return Box::new(future::err(RequestError::UserError(format!(
+                        "bad credential_source.url: {}",
+                        e
+                    ))))
+                }
+            };
+            let format = source.format.clone();
+            Box::new(
+                self.client
+                    .request(request)
+                    .map_err(RequestError::ClientError)
+                    .and_then(|response| {
+                        let status = response.status();
+                        response
+                            .into_body()
+                            .concat2()
+                            .map_err(RequestError::ClientError)
+                            .map(move |body| (status, body))
+                    })
+                    .and_then(move |(status, body)| {
+                        let body = String::from_utf8_lossy(&body);
+                        if !status.is_success() {
+                            return Err(RequestError::BadServerResponse(format!(
+                                "fetching the subject token failed with status {}: {}",
+                                status, body
+                            )));
+                        }
+                        parse_subject_token(&format, &body)
+                    }),
+            )
+        } else if let Some(ref executable) = source.executable {
+            let (executable, secret) = (executable.clone(), self.secret.clone());
+            Box::new(futures::lazy(move || {
+                run_executable(&executable, &secret, executables_allowed())
+            }))
+        } else {
+            Box::new(future::err(RequestError::UserError(
+                "credential_source names neither a file, a URL nor an executable".to_string(),
+            )))
+        }
+    }
+}
+
+impl<C: 'static> GetToken for ExternalAccountAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        if let Ok(Some(token)) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(hash, &scopes.iter().map(|s| s.as_str()).collect())
+        {
+            if !token.expired() {
+                return Box::new(future::ok(token));
+            }
+        }
+
+        let client = self.client.clone();
+        let exchange = self.exchange.clone();
+        let secret = self.secret.clone();
+        let cache = self.cache.clone();
+        let token = self.subject_token().and_then(move |subject_token| {
+            match secret.service_account_impersonation_url.clone() {
+                None => Box::new(
+                    exchange
+                        .exchange(subject_token, &secret.subject_token_type, scopes.clone())
+                        .map(move |exchanged| (exchanged.token, scopes)),
+                )
+                    as Box<dyn Future<Item = (Token, Vec<String>), Error = RequestError> + Send>,
+                Some(url) => {
+                    let lifetime = secret
+                        .service_account_impersonation
+                        .as_ref()
+                        .and_then(|i| i.token_lifetime_seconds)
+                        .unwrap_or(3600);
+                    let request = GenerateAccessTokenRequest {
+                        delegates: Vec::new(),
+                        scope: scopes.clone(),
+                        lifetime: format!("{}s", lifetime),
+                    };
+                    Box::new(
+                        exchange
+                            .exchange(
+                                subject_token,
+                                &secret.subject_token_type,
+                                vec![CLOUD_PLATFORM_SCOPE],
+                            )
+                            .and_then(move |exchanged| {
+                                generate_access_token(client, url, exchanged.token, request)
+                            })
+                            .map(move |token| (token, scopes)),
+                    )
+                }
+            }
+        });
+        Box::new(token.map(move |(token, scopes)| {
+            let _ = cache.lock().unwrap().set(
+                hash,
+                &scopes.iter().map(|s| s.as_str()).collect(),
+                Some(token.clone()),
+            );
+            token
+        }))
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Returns an empty ApplicationSecret as external account tokens don't need to be refreshed
+    /// (they are simply reissued).
+    fn application_secret(&self) -> ApplicationSecret {
+        ApplicationSecret::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper_rustls::HttpsConnector;
+
+    fn client() -> hyper::Client<HttpsConnector<hyper::client::HttpConnector>> {
+        hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1))
+    }
+
+    fn secret(credential_source: &str, impersonate: bool) -> ExternalAccountSecret {
+        let impersonation_url = if impersonate {
+            format!(
+                r#""service_account_impersonation_url": "{}/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken","#,
+                mockito::server_url()
+            )
+        } else {
+            String::new()
+        };
+        serde_json::from_str(&format!(
+            r#"{{
+  "type": "external_account",
+  "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+  "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+  "token_url": "{}/external/token",
+  {}
+  "credential_source": {}
+}}"#,
+            mockito::server_url(),
+            impersonation_url,
+            credential_source
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_subject_token() {
+        assert_eq!(parse_subject_token(&None, "token\n").unwrap(), "token");
+        let json = Some(CredentialSourceFormat {
+            format_type: "json".to_string(),
+            subject_token_field_name: Some("access_token".to_string()),
+        });
+        assert_eq!(
+            parse_subject_token(&json, r#"{"access_token": "token"}"#).unwrap(),
+            "token"
+        );
+        assert!(parse_subject_token(&json, r#"{"id_token": "token"}"#).is_err());
+    }
+
+    #[test]
+    fn test_external_account_end2end() {
+        let dir = env::temp_dir().join(format!("yup-oauth2-external-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token");
+        fs::write(&token_file, "subjecttoken\n").unwrap();
+        let source = format!(r#"{{"file": "{}"}}"#, token_file.display());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Token exchange only.
+        {
+            let _m = mockito::mock("POST", "/external/token")
+                .match_body(mockito::Matcher::Regex(
+                    "^grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange&subject_token=subjecttoken&subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt&.*&audience=.*&scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fpubsub$".to_string(),
+                ))
+                .with_status(200)
+                .with_body(r#"{"access_token": "ststoken", "issued_token_type": "urn:ietf:params:oauth:token-type:access_token", "token_type": "Bearer", "expires_in": 3600}"#)
+                .expect(1)
+                .create();
+            let mut access = ExternalAccountAccess::new(secret(&source, false))
+                .hyper_client(client())
+                .build();
+            for _ in 0..2 {
+                let token = rt
+                    .block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+                    .unwrap();
+                assert_eq!(token.access_token, "ststoken");
+            }
+            _m.assert();
+        }
+        // Token exchange and impersonation.
+        {
+            let _sts = mockito::mock("POST", "/external/token")
+                .match_body(mockito::Matcher::Regex(
+                    ".*scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform$"
+                        .to_string(),
+                ))
+                .with_status(200)
+                .with_body(r#"{"access_token": "ststoken", "issued_token_type": "urn:ietf:params:oauth:token-type:access_token", "token_type": "Bearer", "expires_in": 3600}"#)
+                .expect(1)
+                .create();
+            let _iam = mockito::mock(
+                "POST",
+                "/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken",
+            )
+            .match_header("authorization", "Bearer ststoken")
+            .with_status(200)
+            .with_body(format!(
+                r#"{{"accessToken": "satoken", "expireTime": "{}"}}"#,
+                (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339()
+            ))
+            .expect(1)
+            .create();
+            let mut access = ExternalAccountAccess::new(secret(&source, true))
+                .hyper_client(client())
+                .build();
+            let token = rt
+                .block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+                .unwrap();
+            assert_eq!(token.access_token, "satoken");
+            _sts.assert();
+            _iam.assert();
+        }
+        // Rejected subject token.
+        {
+            let _m = mockito::mock("POST", "/external/token")
+                .with_status(400)
+                .with_body(r#"{"error": "invalid_grant", "error_description": "The audience does not match"}"#)
+                .create();
+            let mut access = ExternalAccountAccess::new(secret(&source, false))
+                .hyper_client(client())
+                .build();
+            match rt.block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"])) {
+                Err(RequestError::NegativeServerResponse(ref e, _)) => {
+                    assert_eq!(e, "invalid_grant")
+                }
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_executable_source() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("yup-oauth2-executable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("token.sh");
+        fs::write(
+            &script,
+            "#!/bin/sh\necho '{\"version\": 1, \"success\": true, \"token_type\": \"urn:ietf:params:oauth:token-type:id_token\", \"id_token\": \"'$GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE'\", \"padding\": \"'$(head -c $1 /dev/zero | tr '\\0' x)'\"}'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let run = |command: &str, timeout_millis: u64, allowed: bool| {
+            let source = format!(
+                r#"{{"executable": {{"command": "{}", "timeout_millis": {}}}}}"#,
+                command, timeout_millis
+            );
+            let secret = secret(&source, false);
+            let executable = secret.credential_source.executable.clone().unwrap();
+            run_executable(&executable, &secret, allowed)
+        };
+        let token = rt.block_on(run(
+            // The script's output exceeds the capacity of a pipe.
+            &format!("{} 262144", script.display()),
+            30_000,
+            true,
+        ));
+        let disabled = rt.block_on(run(&script.display().to_string(), 30_000, false));
+        let failed = rt.block_on(run("/bin/sh -c 'echo oops >&2; exit 3'", 30_000, true));
+        let timed_out = rt.block_on(run("/bin/sleep 10", 100, true));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(token.unwrap(), "urn:ietf:params:oauth:token-type:jwt");
+        assert!(disabled.is_err());
+        match failed {
+            Err(RequestError::BadServerResponse(ref e)) => {
+                assert!(e.contains('3') && e.ends_with(": oops"), "{}", e)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        match timed_out {
+            Err(RequestError::LowLevelError(ref e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command(r#"/bin/tool  --flag "a b" 'c "d"' e\ f "" "g\"h""#).unwrap(),
+            vec![
+                "/bin/tool",
+                "--flag",
+                "a b",
+                r#"c "d""#,
+                "e f",
+                "",
+                r#"g"h"#
+            ]
+        );
+        assert!(split_command("").unwrap().is_empty());
+        assert!(split_command("/bin/tool 'a").is_err());
+        assert!(split_command("/bin/tool a\\").is_err());
+    }
+}
@@ -13,8 +13,11 @@ use std::fs;
 use std::io::{self, Read};
 use std::path::Path;
 
+use crate::authorized_user::AuthorizedUserSecret;
+use crate::external_account::ExternalAccountSecret;
 use crate::service_account::ServiceAccountKey;
 use crate::types::{ApplicationSecret, ConsoleApplicationSecret};
+use crate::verifier::Jwks;
 
 /// Read an application secret from a file.
 pub fn read_application_secret(path: &Path) -> io::Result<ApplicationSecret> {
@@ -61,3 +64,46 @@ pub fn service_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<Serv
         Ok(decoded) => Ok(decoded),
     }
 }
+
+/// Read authorized user credentials from a JSON file, such as the one written by
+/// `gcloud auth application-default login`.
+pub fn authorized_user_secret_from_file<S: AsRef<Path>>(
+    path: S,
+) -> io::Result<AuthorizedUserSecret> {
+    let mut secret = String::new();
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    file.read_to_string(&mut secret)?;
+
+    match serde_json::from_str(&secret) {
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        Ok(decoded) => Ok(decoded),
+    }
+}
+
+/// Read external account credentials (Workload Identity Federation) from a JSON file, such as the
+/// one written by `gcloud iam workload-identity-pools create-cred-config`.
+pub fn external_account_secret_from_file<S: AsRef<Path>>(
+    path: S,
+) -> io::Result<ExternalAccountSecret> {
+    let mut secret = String::new();
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    file.read_to_string(&mut secret)?;
+
+    match serde_json::from_str(&secret) {
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        Ok(decoded) => Ok(decoded),
+    }
+}
+
+/// Read a JSON Web Key Set (`{"keys": [...]}`) from a file, e.g. to verify JWTs offline using
+/// `JwtVerifier`.
+pub fn jwks_from_file<S: AsRef<Path>>(path: S) -> io::Result<Jwks> {
+    let mut jwks = String::new();
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    file.read_to_string(&mut jwks)?;
+
+    match serde_json::from_str(&jwks) {
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        Ok(decoded) => Ok(decoded),
+    }
+}
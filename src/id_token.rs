@@ -0,0 +1,183 @@
+//! This module decodes the claims of OpenID Connect ID tokens, as returned by the flows alongside
+//! the access token if the `openid` scope was requested.
+//!
+//! Decoding does not verify the token's signature; only rely on the claims of ID tokens that were
+//! received directly from the token endpoint over TLS.
+//!
+//! Resources:
+//! - [OpenID Connect Core, ID Token](https://openid.net/specs/openid-connect-core-1_0.html#IDToken)
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::types::RequestError;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+/// The `aud` claim, which is either a single audience or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_audience<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Audience::One(aud) => vec![aud],
+        Audience::Many(auds) => auds,
+    })
+}
+
+/// The claims of an OpenID Connect ID token.
+///
+/// Obtain them from a `Token` using `Token::id_token_claims()`.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct IdTokenClaims {
+    /// The issuer, e.g. `https://accounts.google.com`.
+    pub iss: String,
+    /// The identifier of the user (or service account) at the issuer.
+    pub sub: String,
+    /// The audiences the token is intended for; contains the client ID.
+    #[serde(deserialize_with = "deserialize_audience")]
+    pub aud: Vec<String>,
+    /// Seconds since epoch after which the token must not be accepted anymore.
+    pub exp: i64,
+    /// Seconds since epoch at which the token was issued.
+    pub iat: i64,
+    /// Seconds since epoch before which the token must not be accepted.
+    pub nbf: Option<i64>,
+    /// The party the token was issued to.
+    pub azp: Option<String>,
+    /// The value passed in the authentication request to prevent replay attacks.
+    pub nonce: Option<String>,
+    /// The user's email address, if the `email` scope was granted.
+    pub email: Option<String>,
+    /// Whether the issuer verified the user's email address.
+    pub email_verified: Option<bool>,
+    /// The user's full name, if the `profile` scope was granted.
+    pub name: Option<String>,
+    /// All other claims.
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+impl IdTokenClaims {
+    /// Returns a DateTime object representing the token's expiry date, failing if the `exp`
+    /// claim is out of range.
+    pub fn expiry_date(&self) -> Result<DateTime<Utc>, RequestError> {
+        Utc.timestamp_opt(self.exp, 0).single().ok_or_else(|| {
+            RequestError::BadServerResponse(format!("malformed JWT: invalid exp {}", self.exp))
+        })
+    }
+
+    /// Returns true if the token has expired. A token with an invalid expiry date counts as
+    /// expired.
+    pub fn expired(&self) -> bool {
+        self.expiry_date()
+            .map_or(true, |expiry| expiry <= Utc::now())
+    }
+}
+
+/// Returns the decoded header and claims segments of a compact serialized JWT
+/// (`header.claims.signature`).
+pub(crate) fn decode_segments(jwt: &str) -> Result<(Vec<u8>, Vec<u8>), RequestError> {
+    let mut parts = jwt.split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(claims), Some(_), None) => {
+            let decode = |s: &str| {
+                base64::decode_config(s.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                    .map_err(|e| RequestError::BadServerResponse(format!("malformed JWT: {}", e)))
+            };
+            Ok((decode(header)?, decode(claims)?))
+        }
+        _ => Err(RequestError::BadServerResponse(
+            "malformed JWT: expected three segments".to_string(),
+        )),
+    }
+}
+
+/// Decode the claims of an ID token without verifying it.
+pub(crate) fn decode_claims(id_token: &str) -> Result<IdTokenClaims, RequestError> {
+    let (_, claims) = decode_segments(id_token)?;
+    serde_json::from_slice(&claims).map_err(RequestError::JSONError)
+}
+
+/// Caches ID tokens by audience until shortly before they expire, as told by their `exp` claim.
+#[derive(Clone, Default)]
+pub(crate) struct IdTokenCache(Arc<Mutex<HashMap<String, (String, DateTime<Utc>)>>>);
+
+impl IdTokenCache {
+    /// Returns the cached token for `audience`, unless it expires within the next minute.
+    pub(crate) fn get(&self, audience: &str) -> Option<String> {
+        match self.0.lock().unwrap().get(audience) {
+            Some((token, expiry)) if *expiry - chrono::Duration::minutes(1) > Utc::now() => {
+                Some(token.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Caches `token` for `audience`, failing if it can not be decoded.
+    pub(crate) fn set(&self, audience: String, token: String) -> Result<String, RequestError> {
+        let expiry = decode_claims(&token)?.expiry_date()?;
+        self.0
+            .lock()
+            .unwrap()
+            .insert(audience, (token.clone(), expiry));
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(claims: &str) -> String {
+        format!(
+            "{}.{}.c2lnbmF0dXJl",
+            base64::encode_config(r#"{"alg":"RS256","typ":"JWT"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_decode_claims() {
+        let token = jwt(
+            r#"{"iss": "https://accounts.google.com", "sub": "1234", "aud": "client-id",
+                "exp": 1600000000, "iat": 1599996400, "email": "user@example.com",
+                "email_verified": true, "hd": "example.com"}"#,
+        );
+        let claims = decode_claims(&token).unwrap();
+        assert_eq!(claims.iss, "https://accounts.google.com");
+        assert_eq!(claims.sub, "1234");
+        assert_eq!(claims.aud, vec!["client-id".to_string()]);
+        assert_eq!(claims.exp, 1600000000);
+        assert_eq!(claims.email, Some("user@example.com".to_string()));
+        assert_eq!(claims.email_verified, Some(true));
+        assert_eq!(claims.other["hd"], "example.com");
+        assert!(claims.expired());
+
+        let token = jwt(
+            r#"{"iss": "https://issuer", "sub": "1234", "aud": ["a", "b"], "exp": 1, "iat": 0}"#,
+        );
+        assert_eq!(decode_claims(&token).unwrap().aud, vec!["a", "b"]);
+
+        let claims = decode_claims(&jwt(
+            r#"{"iss": "https://issuer", "sub": "1234", "aud": "a", "exp": 9223372036854775807,
+                "iat": 0}"#,
+        ))
+        .unwrap();
+        match claims.expiry_date() {
+            Err(RequestError::BadServerResponse(_)) => {}
+            other => panic!("unexpected expiry date {:?}", other.ok()),
+        }
+        assert!(claims.expired());
+
+        assert!(decode_claims("not-a-jwt").is_err());
+        assert!(decode_claims(&jwt(r#"{"sub": "1234"}"#)).is_err());
+    }
+}
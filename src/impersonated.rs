@@ -0,0 +1,401 @@
+//! This module provides a token source (`GetToken`) that impersonates a service account: It
+//! exchanges the token of a base credential, such as `ServiceAccountAccess`, `MetadataAccess` or
+//! an `Authenticator`, for a token of the target service account using the IAM Credentials API.
+//! The base credential needs the `roles/iam.serviceAccountTokenCreator` role on the target (or
+//! on the first service account of the delegation chain).
+//!
+//! Resources:
+//! - [Creating short-lived service account credentials](https://cloud.google.com/iam/docs/creating-short-lived-service-account-credentials)
+
+use std::sync::{Arc, Mutex};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+use futures::{future, prelude::*};
+use hyper::header;
+
+/// The base URL of Google's IAM Credentials API.
+pub const IAM_CREDENTIALS_URL: &str = "https://iamcredentials.googleapis.com";
+
+/// The scope the base credential's token is requested for.
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// A token source (`GetToken`) yielding tokens of a target service account, obtained using the
+/// token of a base credential. Like `ServiceAccountAccess`, it caches tokens and requests new ones
+/// once they have expired, so there is no need to wrap it in an `Authenticator`.
+///
+/// ```no_run
+/// use futures::prelude::*;
+/// use yup_oauth2::{GetToken, ImpersonatedAccess, MetadataAccess};
+///
+/// let mut access = ImpersonatedAccess::new(
+///     MetadataAccess::new().build(),
+///     "deployer@my-project.iam.gserviceaccount.com".to_string(),
+/// )
+/// .lifetime(chrono::Duration::minutes(10))
+/// .build();
+/// let fut = access
+///     .token(vec!["https://www.googleapis.com/auth/cloud-platform"])
+///     .map(|token| println!("The token is {:?}", token))
+///     .map_err(|e| println!("error: {:?}", e));
+/// tokio::run(fut);
+/// ```
+pub struct ImpersonatedAccess<G, C> {
+    client: C,
+    source: G,
+    target_principal: String,
+    delegates: Vec<String>,
+    lifetime: chrono::Duration,
+    iam_credentials_url: String,
+}
+
+impl<G: GetToken> ImpersonatedAccess<G, DefaultHyperClient> {
+    /// Impersonate the service account with the email `target_principal`, using the tokens of
+    /// `source`.
+    pub fn new(source: G, target_principal: String) -> Self {
+        ImpersonatedAccess {
+            client: DefaultHyperClient,
+            source,
+            target_principal,
+            delegates: Vec::new(),
+            lifetime: chrono::Duration::hours(1),
+            iam_credentials_url: IAM_CREDENTIALS_URL.to_string(),
+        }
+    }
+}
+
+impl<G, C> ImpersonatedAccess<G, C>
+where
+    G: GetToken,
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ImpersonatedAccess<G, NewC> {
+        ImpersonatedAccess {
+            client: hyper_client,
+            source: self.source,
+            target_principal: self.target_principal,
+            delegates: self.delegates,
+            lifetime: self.lifetime,
+            iam_credentials_url: self.iam_credentials_url,
+        }
+    }
+
+    /// The emails of the service accounts in the delegation chain. Each of them needs the
+    /// `roles/iam.serviceAccountTokenCreator` role on the next one, the last one on the target.
+    pub fn delegates(self, delegates: Vec<String>) -> Self {
+        ImpersonatedAccess { delegates, ..self }
+    }
+
+    /// How long the tokens are valid. The default (and, unless the organization allows longer
+    /// lifetimes, the maximum) is one hour.
+    pub fn lifetime(self, lifetime: chrono::Duration) -> Self {
+        ImpersonatedAccess { lifetime, ..self }
+    }
+
+    /// Use the provided IAM Credentials API base URL. The default is `IAM_CREDENTIALS_URL`.
+    pub fn iam_credentials_url(self, url: String) -> Self {
+        ImpersonatedAccess {
+            iam_credentials_url: url,
+            ..self
+        }
+    }
+
+    /// Build the configured ImpersonatedAccess.
+    pub fn build(self) -> impl GetToken {
+        ImpersonatedAccessImpl {
+            client: self.client.build_hyper_client(),
+            source: self.source,
+            target_principal: self.target_principal,
+            delegates: self.delegates,
+            lifetime: self.lifetime,
+            iam_credentials_url: self.iam_credentials_url,
+            cache: Arc::new(Mutex::new(MemoryStorage::default())),
+        }
+    }
+}
+
+struct ImpersonatedAccessImpl<G, C> {
+    client: hyper::Client<C, hyper::Body>,
+    source: G,
+    target_principal: String,
+    delegates: Vec<String>,
+    lifetime: chrono::Duration,
+    iam_credentials_url: String,
+    cache: Arc<Mutex<MemoryStorage>>,
+}
+
+/// The schema of a `generateAccessToken` request.
+#[derive(Serialize)]
+pub(crate) struct GenerateAccessTokenRequest {
+    pub(crate) delegates: Vec<String>,
+    pub(crate) scope: Vec<String>,
+    pub(crate) lifetime: String,
+}
+
+/// The schema of a `generateAccessToken` response.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+}
+
+/// The schema of an error returned by Google APIs.
+#[derive(Deserialize)]
+struct ApiError {
+    error: ApiErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorDetails {
+    message: String,
+    status: Option<String>,
+}
+
+/// Exchange `source_token` for a token of the service account named in the `generateAccessToken`
+/// `url`, with the given scopes.
+pub(crate) fn generate_access_token<C: 'static + hyper::client::connect::Connect>(
+    client: hyper::Client<C>,
+    url: String,
+    source_token: Token,
+    request: GenerateAccessTokenRequest,
+) -> impl Future<Item = Token, Error = RequestError> {
+    let body = serde_json::to_string(&request).unwrap();
+    hyper::Request::post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", source_token.access_token),
+        )
+        .body(hyper::Body::from(body))
+        .into_future()
+        .map_err(|e| RequestError::BadServerResponse(format!("bad IAM Credentials URL: {}", e)))
+        .and_then(move |request| client.request(request).map_err(RequestError::ClientError))
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map_err(RequestError::ClientError)
+                .map(move |body| (status, body))
+        })
+        .and_then(|(status, body)| {
+            if !status.is_success() {
+                return Err(match serde_json::from_slice::<ApiError>(&body) {
+                    Ok(e) => RequestError::NegativeServerResponse(
+                        e.error.status.unwrap_or_else(|| status.to_string()),
+                        Some(e.error.message),
+                    ),
+                    Err(_) => RequestError::BadServerResponse(format!(
+                        "generateAccessToken failed with status {}: {}",
+                        status,
+                        String::from_utf8_lossy(&body)
+                    )),
+                });
+            }
+            let response: GenerateAccessTokenResponse =
+                serde_json::from_slice(&body).map_err(RequestError::JSONError)?;
+            let expiry =
+                chrono::DateTime::parse_from_rfc3339(&response.expire_time).map_err(|e| {
+                    RequestError::BadServerResponse(format!(
+                        "bad expireTime '{}': {}",
+                        response.expire_time, e
+                    ))
+                })?;
+            Ok(Token {
+                access_token: response.access_token,
+                refresh_token: None,
+                token_type: "Bearer".to_string(),
+                expires_in: None,
+                expires_in_timestamp: Some(expiry.timestamp()),
+                scope: None,
+                id_token: None,
+            })
+        })
+}
+
+impl<G, C: 'static> GetToken for ImpersonatedAccessImpl<G, C>
+where
+    G: GetToken,
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        if let Ok(Some(token)) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(hash, &scopes.iter().map(|s| s.as_str()).collect())
+        {
+            if !token.expired() {
+                return Box::new(future::ok(token));
+            }
+        }
+
+        let url = format!(
+            "{}/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+            self.iam_credentials_url, self.target_principal
+        );
+        let request = GenerateAccessTokenRequest {
+            delegates: self
+                .delegates
+                .iter()
+                .map(|d| format!("projects/-/serviceAccounts/{}", d))
+                .collect(),
+            scope: scopes.clone(),
+            lifetime: format!("{}s", self.lifetime.num_seconds()),
+        };
+        let client = self.client.clone();
+        let cache = self.cache.clone();
+        Box::new(
+            self.source
+                .token(vec![CLOUD_PLATFORM_SCOPE])
+                .and_then(move |source_token| {
+                    generate_access_token(client, url, source_token, request)
+                })
+                .map(move |token| {
+                    let _ = cache.lock().unwrap().set(
+                        hash,
+                        &scopes.iter().map(|s| s.as_str()).collect(),
+                        Some(token.clone()),
+                    );
+                    token
+                }),
+        )
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Returns an empty ApplicationSecret as impersonated tokens don't need to be refreshed
+    /// (they are simply reissued).
+    fn application_secret(&self) -> ApplicationSecret {
+        ApplicationSecret::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper_rustls::HttpsConnector;
+
+    /// A base credential handing out a fixed token.
+    struct FixedSource;
+
+    impl GetToken for FixedSource {
+        fn token<I, T>(
+            &mut self,
+            scopes: I,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+            assert_eq!(scopes, vec![CLOUD_PLATFORM_SCOPE]);
+            Box::new(future::ok(Token {
+                access_token: "sourcetoken".to_string(),
+                refresh_token: None,
+                token_type: "Bearer".to_string(),
+                expires_in: None,
+                expires_in_timestamp: None,
+                scope: None,
+                id_token: None,
+            }))
+        }
+        fn api_key(&mut self) -> Option<String> {
+            None
+        }
+        fn application_secret(&self) -> ApplicationSecret {
+            ApplicationSecret::default()
+        }
+    }
+
+    #[test]
+    fn test_impersonate_end2end() {
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let expire_time = (chrono::Utc::now() + chrono::Duration::minutes(10)).to_rfc3339();
+
+        // Success, and the token is cached afterwards.
+        {
+            let _m = mockito::mock(
+                "POST",
+                "/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken",
+            )
+            .match_header("authorization", "Bearer sourcetoken")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "delegates": ["projects/-/serviceAccounts/middle@project.iam.gserviceaccount.com"],
+                "scope": ["https://www.googleapis.com/auth/pubsub"],
+                "lifetime": "600s",
+            })))
+            .with_status(200)
+            .with_body(format!(
+                r#"{{"accessToken": "impersonatedtoken", "expireTime": "{}"}}"#,
+                expire_time
+            ))
+            .expect(1)
+            .create();
+            let mut access = ImpersonatedAccess::new(
+                FixedSource,
+                "target@project.iam.gserviceaccount.com".to_string(),
+            )
+            .hyper_client(client.clone())
+            .delegates(vec!["middle@project.iam.gserviceaccount.com".to_string()])
+            .lifetime(chrono::Duration::minutes(10))
+            .iam_credentials_url(mockito::server_url())
+            .build();
+            for _ in 0..2 {
+                let token = rt
+                    .block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"]))
+                    .unwrap();
+                assert_eq!(token.access_token, "impersonatedtoken");
+                assert!(!token.expired());
+            }
+            _m.assert();
+        }
+        // Permission denied.
+        {
+            let _m = mockito::mock(
+                "POST",
+                "/v1/projects/-/serviceAccounts/other@project.iam.gserviceaccount.com:generateAccessToken",
+            )
+            .with_status(403)
+            .with_body(r#"{"error": {"code": 403, "message": "The caller does not have permission", "status": "PERMISSION_DENIED"}}"#)
+            .create();
+            let mut access = ImpersonatedAccess::new(
+                FixedSource,
+                "other@project.iam.gserviceaccount.com".to_string(),
+            )
+            .hyper_client(client)
+            .iam_credentials_url(mockito::server_url())
+            .build();
+            match rt.block_on(access.token(vec!["https://www.googleapis.com/auth/pubsub"])) {
+                Err(RequestError::NegativeServerResponse(ref status, Some(ref message))) => {
+                    assert_eq!(status, "PERMISSION_DENIED");
+                    assert_eq!(message, "The caller does not have permission");
+                }
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+            _m.assert();
+        }
+    }
+}
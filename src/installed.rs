@@ -3,6 +3,7 @@
 // Refer to the project root for licensing information.
 //
 use std::convert::AsRef;
+use std::io;
 use std::sync::{Arc, Mutex};
 
 use futures::prelude::*;
@@ -10,22 +11,66 @@ use futures::stream::Stream;
 use futures::sync::oneshot;
 use hyper;
 use hyper::{header, StatusCode, Uri};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
 use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
 
 use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate};
+use crate::provider::Provider;
 use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
 
-const OOB_REDIRECT_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
+/// Returns a URL-safe string encoding `len` random bytes, for use as PKCE code verifier or
+/// `state` parameter.
+fn random_urlsafe_string(len: usize) -> Result<String, RequestError> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| {
+        RequestError::LowLevelError(io::Error::new(
+            io::ErrorKind::Other,
+            "Couldn't generate random string",
+        ))
+    })?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// A PKCE code verifier together with its derived challenge, see
+/// [RFC 7636](https://tools.ietf.org/html/rfc7636).
+struct PkceChallenge {
+    /// Sent with the token request.
+    verifier: String,
+    /// Sent with the authorization request; the base64url-encoded SHA-256 of `verifier`.
+    challenge: String,
+}
+
+impl PkceChallenge {
+    /// Generate a new random code verifier (32 bytes of entropy, 43 characters).
+    fn new() -> Result<PkceChallenge, RequestError> {
+        random_urlsafe_string(32).map(PkceChallenge::from_verifier)
+    }
+
+    /// Derive the S256 challenge for the given code verifier.
+    fn from_verifier(verifier: String) -> PkceChallenge {
+        let digest = digest::digest(&digest::SHA256, verifier.as_bytes());
+        let challenge = base64::encode_config(digest.as_ref(), base64::URL_SAFE_NO_PAD);
+        PkceChallenge {
+            verifier,
+            challenge,
+        }
+    }
+}
 
 /// Assembles a URL to request an authorization token (with user interaction).
-/// Note that the redirect_uri here has to be either None or some variation of
-/// http://localhost:{port}, or the authorization won't work (error "redirect_uri_mismatch")
+/// Note that the redirect_uri here has to be either the provider's OOB redirect URI or some
+/// variation of http://localhost:{port}, or the authorization won't work (error
+/// "redirect_uri_mismatch"). `auth_params` are the provider's additional parameters.
 fn build_authentication_request_url<'a, T, I>(
     auth_uri: &str,
     client_id: &str,
     scopes: I,
-    redirect_uri: Option<String>,
+    auth_params: &[(String, String)],
+    redirect_uri: &str,
+    state: Option<&str>,
+    code_challenge: Option<&str>,
 ) -> String
 where
     T: AsRef<str> + 'a,
@@ -41,18 +86,23 @@ where
     scopes_string.pop();
 
     url.push_str(auth_uri);
-    vec![
-        format!("?scope={}", scopes_string),
-        format!("&access_type=offline"),
-        format!(
-            "&redirect_uri={}",
-            redirect_uri.unwrap_or(OOB_REDIRECT_URI.to_string())
-        ),
-        format!("&response_type=code"),
-        format!("&client_id={}", client_id),
-    ]
-    .into_iter()
-    .fold(url, |mut u, param| {
+    let mut params = vec![format!("?scope={}", scopes_string)];
+    params.extend(
+        auth_params
+            .iter()
+            .map(|(name, value)| format!("&{}={}", name, value)),
+    );
+    params.push(format!("&redirect_uri={}", redirect_uri));
+    params.push(format!("&response_type=code"));
+    params.push(format!("&client_id={}", client_id));
+    if let Some(state) = state {
+        params.push(format!("&state={}", state));
+    }
+    if let Some(challenge) = code_challenge {
+        params.push(format!("&code_challenge={}", challenge));
+        params.push("&code_challenge_method=S256".to_string());
+    }
+    params.into_iter().fold(url, |mut u, param| {
         u.push_str(&percent_encode(param.as_ref(), QUERY_ENCODE_SET).to_string());
         u
     })
@@ -85,12 +135,14 @@ pub struct InstalledFlowImpl<FD: FlowDelegate, C: hyper::client::connect::Connec
     client: hyper::client::Client<C, hyper::Body>,
     fd: FD,
     appsecret: ApplicationSecret,
+    provider: Provider,
+    pkce: bool,
 }
 
 /// cf. https://developers.google.com/identity/protocols/OAuth2InstalledApp#choosingredirecturi
 pub enum InstalledFlowReturnMethod {
     /// Involves showing a URL to the user and asking to copy a code from their browser
-    /// (default)
+    /// (default). No `state` parameter is sent, as a pasted code can't be checked against it.
     Interactive,
     /// Involves spinning up a local HTTP server and Google redirecting the browser to
     /// the server with a URL containing the code (preferred, but not as reliable).
@@ -108,18 +160,29 @@ pub struct InstalledFlow<FD: FlowDelegate> {
     method: InstalledFlowReturnMethod,
     flow_delegate: FD,
     appsecret: ApplicationSecret,
+    provider: Provider,
+    pkce: bool,
 }
 
 impl InstalledFlow<DefaultFlowDelegate> {
-    /// Create a new InstalledFlow with the provided secret and method.
+    /// Create a new InstalledFlow with the provided secret and method. PKCE is enabled by
+    /// default. The secret's endpoints are used with Google's quirks; use `provider()` for other
+    /// providers.
     pub fn new(
         secret: ApplicationSecret,
         method: InstalledFlowReturnMethod,
     ) -> InstalledFlow<DefaultFlowDelegate> {
+        let provider = Provider {
+            auth_uri: Some(secret.auth_uri.clone()).filter(|uri| !uri.is_empty()),
+            token_uri: secret.token_uri.clone(),
+            ..Provider::google()
+        };
         InstalledFlow {
             method,
             flow_delegate: DefaultFlowDelegate,
             appsecret: secret,
+            provider,
+            pkce: true,
         }
     }
 }
@@ -134,6 +197,30 @@ where
             method: self.method,
             flow_delegate: delegate,
             appsecret: self.appsecret,
+            provider: self.provider,
+            pkce: self.pkce,
+        }
+    }
+
+    /// Use the provided provider's endpoints (overriding those of the application secret) and
+    /// quirks. This also enables or disables PKCE as supported by the provider.
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.appsecret.token_uri = provider.token_uri.clone();
+        InstalledFlow {
+            pkce: provider.pkce,
+            provider,
+            ..self
+        }
+    }
+
+    /// Enable or disable PKCE ([RFC 7636](https://tools.ietf.org/html/rfc7636)). When enabled
+    /// (the default), an S256 code challenge is sent with the authorization request and the
+    /// matching code verifier with the token request. Disable it for providers rejecting these
+    /// parameters.
+    pub fn pkce(self, enabled: bool) -> Self {
+        InstalledFlow {
+            pkce: enabled,
+            ..self
         }
     }
 }
@@ -150,6 +237,8 @@ where
             method: self.method,
             fd: self.flow_delegate,
             appsecret: self.appsecret,
+            provider: self.provider,
+            pkce: self.pkce,
             client,
         }
     }
@@ -175,32 +264,62 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
             InstalledFlowReturnMethod::HTTPRedirectEphemeral => Some(0),
             _ => None,
         };
-        let server = if let Some(port) = server_bind_port {
-            match InstalledFlowServer::new(port) {
-                Result::Err(e) => Err(RequestError::ClientError(e)),
-                Result::Ok(server) => Ok(Some(server)),
-            }
-        } else {
-            Ok(None)
-        };
-        let port = if let Ok(Some(ref srv)) = server {
+        // The state parameter protects the redirect server against injected authorization codes;
+        // it isn't sent when the user pastes the code.
+        let server = random_urlsafe_string(16).and_then(|state| {
+            let server = if let Some(port) = server_bind_port {
+                match InstalledFlowServer::new(port, state.clone()) {
+                    Result::Err(e) => return Err(RequestError::ClientError(e)),
+                    Result::Ok(server) => Some(server),
+                }
+            } else {
+                None
+            };
+            Ok((server, state))
+        });
+        let port = if let Ok((Some(ref srv), _)) = server {
             Some(srv.port)
         } else {
             None
         };
+        let pkce = if self.pkce {
+            PkceChallenge::new().map(Some)
+        } else {
+            Ok(None)
+        };
         let client = self.client.clone();
         let (appsecclone, appsecclone2) = (self.appsecret.clone(), self.appsecret.clone());
+        let provider = self.provider.clone();
+        let oob_redirect_uri = self.provider.oob_redirect_uri.clone();
         let auth_delegate = self.fd.clone();
         server
+            .and_then(|(server, state)| pkce.map(|pkce| (server, state, pkce)))
             .into_future()
             // First: Obtain authorization code from user.
-            .and_then(move |server| {
-                Self::ask_authorization_code(server, auth_delegate, &appsecclone, scopes.iter())
+            .and_then(move |(server, state, pkce)| {
+                let code_verifier = pkce.as_ref().map(|p| p.verifier.clone());
+                Self::ask_authorization_code(
+                    server,
+                    auth_delegate,
+                    &appsecclone,
+                    &provider,
+                    scopes.iter(),
+                    &state,
+                    pkce.as_ref().map(|p| p.challenge.as_str()),
+                )
+                .map(move |authcode| (authcode, code_verifier))
             })
             // Exchange the authorization code provided by Google/the provider for a refresh and an
             // access token.
-            .and_then(move |authcode| {
-                let request = Self::request_token(appsecclone2, authcode, rduri, port);
+            .and_then(move |(authcode, code_verifier)| {
+                let request = Self::request_token(
+                    appsecclone2,
+                    authcode,
+                    code_verifier,
+                    rduri,
+                    oob_redirect_uri,
+                    port,
+                );
                 let result = client.request(request);
                 // Handle result here, it makes ownership tracking easier.
                 result
@@ -246,6 +365,8 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
                         token_type: tokens.token_type.unwrap(),
                         expires_in: tokens.expires_in,
                         expires_in_timestamp: None,
+                        scope: tokens.scope,
+                        id_token: tokens.id_token,
                     };
 
                     token.set_expiry_absolute();
@@ -263,18 +384,52 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
         server: Option<InstalledFlowServer>,
         mut auth_delegate: FD,
         appsecret: &ApplicationSecret,
+        provider: &Provider,
         scopes: S,
+        state: &str,
+        code_challenge: Option<&str>,
     ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
     where
         T: AsRef<str> + 'a,
         S: Iterator<Item = &'a T>,
     {
+        let auth_uri = match provider.auth_uri {
+            Some(ref uri) => uri,
+            None => {
+                return Box::new(
+                    Err(RequestError::UserError(
+                        "the provider has no authorization endpoint".to_string(),
+                    ))
+                    .into_future(),
+                )
+            }
+        };
         if server.is_none() {
+            let redirect_uri = match auth_delegate
+                .redirect_uri()
+                .or_else(|| provider.oob_redirect_uri.clone())
+            {
+                Some(uri) => uri,
+                None => {
+                    return Box::new(
+                        Err(RequestError::UserError(
+                            "the provider doesn't display authorization codes; use a redirect \
+                             method"
+                                .to_string(),
+                        ))
+                        .into_future(),
+                    )
+                }
+            };
             let url = build_authentication_request_url(
-                &appsecret.auth_uri,
+                auth_uri,
                 &appsecret.client_id,
                 scopes,
-                auth_delegate.redirect_uri(),
+                &provider.auth_params,
+                &redirect_uri,
+                // The user pastes just the code, so a state could not be checked.
+                None,
+                code_challenge,
             );
             Box::new(
                 auth_delegate
@@ -300,12 +455,15 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
             // The redirect URI must be this very localhost URL, otherwise authorization is refused
             // by certain providers.
             let url = build_authentication_request_url(
-                &appsecret.auth_uri,
+                auth_uri,
                 &appsecret.client_id,
                 scopes,
-                auth_delegate
+                &provider.auth_params,
+                &auth_delegate
                     .redirect_uri()
-                    .or_else(|| Some(format!("http://localhost:{}", server.port))),
+                    .unwrap_or_else(|| format!("http://localhost:{}", server.port)),
+                Some(state),
+                code_challenge,
             );
             Box::new(
                 auth_delegate
@@ -322,29 +480,38 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
     }
 
     /// Sends the authorization code to the provider in order to obtain access and refresh tokens.
+    /// `code_verifier` is the PKCE verifier matching the challenge sent along with the
+    /// authorization request, if any.
     fn request_token<'a>(
         appsecret: ApplicationSecret,
         authcode: String,
+        code_verifier: Option<String>,
         custom_redirect_uri: Option<String>,
+        oob_redirect_uri: Option<String>,
         port: Option<u16>,
     ) -> hyper::Request<hyper::Body> {
         let redirect_uri = custom_redirect_uri.unwrap_or_else(|| match port {
-            None => OOB_REDIRECT_URI.to_string(),
+            None => oob_redirect_uri.unwrap_or_default(),
             Some(port) => format!("http://localhost:{}", port),
         });
 
+        let mut params = vec![
+            ("code".to_string(), authcode.to_string()),
+            ("client_id".to_string(), appsecret.client_id.clone()),
+            ("client_secret".to_string(), appsecret.client_secret.clone()),
+            ("redirect_uri".to_string(), redirect_uri),
+            ("grant_type".to_string(), "authorization_code".to_string()),
+        ];
+        if let Some(code_verifier) = code_verifier {
+            params.push(("code_verifier".to_string(), code_verifier));
+        }
         let body = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(vec![
-                ("code".to_string(), authcode.to_string()),
-                ("client_id".to_string(), appsecret.client_id.clone()),
-                ("client_secret".to_string(), appsecret.client_secret.clone()),
-                ("redirect_uri".to_string(), redirect_uri),
-                ("grant_type".to_string(), "authorization_code".to_string()),
-            ])
+            .extend_pairs(params)
             .finish();
 
         let request = hyper::Request::post(appsecret.token_uri)
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
             .body(hyper::Body::from(body))
             .unwrap(); // TODO: error check
         request
@@ -357,6 +524,8 @@ struct JSONTokenResponse {
     refresh_token: Option<String>,
     token_type: Option<String>,
     expires_in: Option<i64>,
+    scope: Option<String>,
+    id_token: Option<String>,
 
     error: Option<String>,
     error_description: Option<String>,
@@ -370,7 +539,8 @@ struct InstalledFlowServer {
 }
 
 impl InstalledFlowServer {
-    fn new(port: u16) -> Result<InstalledFlowServer, hyper::error::Error> {
+    /// Start a server on the given port. Only redirects carrying the given `state` are accepted.
+    fn new(port: u16, state: String) -> Result<InstalledFlowServer, hyper::error::Error> {
         let (auth_code_tx, auth_code_rx) = oneshot::channel::<String>();
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -378,7 +548,7 @@ impl InstalledFlowServer {
             .pool_size(1)
             .name_prefix("InstalledFlowServer-")
             .build();
-        let service_maker = InstalledFlowServiceMaker::new(auth_code_tx);
+        let service_maker = InstalledFlowServiceMaker::new(auth_code_tx, state);
 
         let addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
         let builder = hyper::server::Server::try_bind(&addr)?;
@@ -443,12 +613,16 @@ impl futures::Future for InstalledFlowHandlerResponseFuture {
 /// Creates InstalledFlowService on demand
 struct InstalledFlowServiceMaker {
     auth_code_tx: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    state: Arc<String>,
 }
 
 impl InstalledFlowServiceMaker {
-    fn new(auth_code_tx: oneshot::Sender<String>) -> InstalledFlowServiceMaker {
+    fn new(auth_code_tx: oneshot::Sender<String>, state: String) -> InstalledFlowServiceMaker {
         let auth_code_tx = Arc::new(Mutex::new(Option::Some(auth_code_tx)));
-        InstalledFlowServiceMaker { auth_code_tx }
+        InstalledFlowServiceMaker {
+            auth_code_tx,
+            state: Arc::new(state),
+        }
     }
 }
 
@@ -463,6 +637,7 @@ impl<Ctx> hyper::service::MakeService<Ctx> for InstalledFlowServiceMaker {
     fn make_service(&mut self, _ctx: Ctx) -> Self::Future {
         let service = InstalledFlowService {
             auth_code_tx: self.auth_code_tx.clone(),
+            state: self.state.clone(),
         };
         futures::future::ok(service)
     }
@@ -471,6 +646,8 @@ impl<Ctx> hyper::service::MakeService<Ctx> for InstalledFlowServiceMaker {
 /// HTTP service handling the redirect from the provider.
 struct InstalledFlowService {
     auth_code_tx: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    /// The `state` parameter sent with the authorization request.
+    state: Arc<String>,
 }
 
 impl hyper::service::Service for InstalledFlowService {
@@ -505,14 +682,20 @@ impl hyper::service::Service for InstalledFlowService {
                         )),
                     }
                 } else {
-                    self.handle_url(url.unwrap());
-                    let response =
-                        hyper::Response::builder()
-                            .status(StatusCode::OK)
-                            .body(hyper::Body::from(
+                    let response = match self.handle_url(url.unwrap()) {
+                        Ok(()) => hyper::Response::builder().status(StatusCode::OK).body(
+                            hyper::Body::from(
                                 "<html><head><title>Success</title></head><body>You may now \
                                  close this window.</body></html>",
-                            ));
+                            ),
+                        ),
+                        Err(msg) => hyper::Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(hyper::Body::from(format!(
+                                "<html><head><title>Error</title></head><body>{}</body></html>",
+                                msg
+                            ))),
+                    };
 
                     match response {
                         Ok(response) => InstalledFlowHandlerResponseFuture::new(Box::new(
@@ -543,25 +726,42 @@ impl hyper::service::Service for InstalledFlowService {
 }
 
 impl InstalledFlowService {
-    fn handle_url(&mut self, url: hyper::Uri) {
+    /// Returns an error message if the request carries a code, but not the expected state.
+    fn handle_url(&mut self, url: hyper::Uri) -> Result<(), &'static str> {
         // The provider redirects to the specified localhost URL, appending the authorization
-        // code, like this: http://localhost:8080/xyz/?code=4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs
+        // code and the state, like this:
+        // http://localhost:8080/xyz/?state=abc&code=4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs
         // We take that code and send it to the ask_authorization_code() function that
         // waits for it.
+        let mut code = None;
+        let mut state = None;
         for (param, val) in form_urlencoded::parse(url.query().unwrap_or("").as_bytes()) {
-            if param == "code".to_string() {
-                let mut auth_code_tx = self.auth_code_tx.lock().unwrap();
-                match auth_code_tx.take() {
-                    Some(auth_code_tx) => {
-                        let _ = auth_code_tx.send(val.to_owned().to_string());
-                    }
-                    None => {
-                        // call to the server after a previous call. Each server is only designed
-                        // to receive a single request.
-                    }
-                };
+            match param.as_ref() {
+                "code" if code.is_none() => code = Some(val.into_owned()),
+                "state" if state.is_none() => state = Some(val.into_owned()),
+                _ => {}
             }
         }
+        let code = match code {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+        // Reject codes not belonging to our authorization request; keep waiting for the right
+        // one.
+        if state.as_deref() != Some(self.state.as_str()) {
+            return Err("Invalid state parameter; the authorization code was not accepted.");
+        }
+        let mut auth_code_tx = self.auth_code_tx.lock().unwrap();
+        match auth_code_tx.take() {
+            Some(auth_code_tx) => {
+                let _ = auth_code_tx.send(code);
+            }
+            None => {
+                // call to the server after a previous call. Each server is only designed
+                // to receive a single request.
+            }
+        };
+        Ok(())
     }
 }
 
@@ -581,6 +781,7 @@ mod tests {
     use crate::authenticator::AuthFlow;
     use crate::authenticator_delegate::FlowDelegate;
     use crate::helper::*;
+    use crate::provider::GOOGLE_OOB_REDIRECT_URI;
     use crate::types::StringError;
 
     #[test]
@@ -621,8 +822,12 @@ mod tests {
                             .into_future(),
                         );
                     }
+                    let state = form_urlencoded::parse(query.as_bytes())
+                        .find(|(k, _)| k == "state")
+                        .map(|(_, v)| v.into_owned())
+                        .unwrap_or_default();
                     let mut rduri = rduri.unwrap();
-                    rduri.push_str(&format!("?code={}", self.0));
+                    rduri.push_str(&format!("?code={}&state={}", self.0, state));
                     let rduri = Uri::from_str(rduri.as_ref()).unwrap();
                     // Hit server.
                     return Box::new(
@@ -660,7 +865,7 @@ mod tests {
         // Successful path.
         {
             let _m = mock("POST", "/token")
-            .match_body(mockito::Matcher::Regex(".*code=authorizationcode.*client_id=9022167.*".to_string()))
+            .match_body(mockito::Matcher::Regex(".*code=authorizationcode.*client_id=9022167.*code_verifier=[A-Za-z0-9_-]{43}$".to_string()))
             .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
             .expect(1)
             .create();
@@ -687,7 +892,7 @@ mod tests {
                     .build_token_getter(client.clone());
             let _m = mock("POST", "/token")
             .match_body(mockito::Matcher::Regex(".*code=authorizationcodefromlocalserver.*client_id=9022167.*".to_string()))
-            .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
+            .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678, "id_token": "eyJhbGciOiJSUzI1NiJ9.eyJpc3MiOiJodHRwczovL2FjY291bnRzLmdvb2dsZS5jb20iLCJzdWIiOiIxMjM0IiwiYXVkIjoiY2xpZW50IiwiZXhwIjoxLCJpYXQiOjB9.c2ln"}"#)
             .expect(1)
             .create();
 
@@ -695,8 +900,11 @@ mod tests {
                 .token(vec!["https://googleapis.com/some/scope"])
                 .and_then(|tok| {
                     assert_eq!("accesstoken", tok.access_token);
-                    assert_eq!("refreshtoken", tok.refresh_token.unwrap());
+                    assert_eq!(Some("refreshtoken"), tok.refresh_token.as_deref());
                     assert_eq!("Bearer", tok.token_type);
+                    let claims = tok.id_token_claims().unwrap().unwrap();
+                    assert_eq!("1234", claims.sub);
+                    assert_eq!(vec!["client"], claims.aud);
                     Ok(())
                 });
             rt.block_on(fut).expect("block on");
@@ -726,6 +934,86 @@ mod tests {
         rt.shutdown_on_idle().wait().expect("shutdown");
     }
 
+    #[test]
+    fn test_redirect_with_wrong_state() {
+        /// Sends an injected code with a wrong state to the redirect server before the real one,
+        /// recording the response to the former.
+        #[derive(Clone)]
+        struct FD(
+            hyper::Client<HttpConnector, hyper::Body>,
+            Arc<Mutex<Option<(StatusCode, String)>>>,
+        );
+        impl FlowDelegate for FD {
+            fn present_user_url<S: AsRef<str> + fmt::Display>(
+                &mut self,
+                url: S,
+                _need_code: bool,
+            ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send>
+            {
+                let uri = Uri::from_str(url.as_ref()).unwrap();
+                let param = |name: &str| {
+                    form_urlencoded::parse(uri.query().unwrap().as_bytes())
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.into_owned())
+                        .unwrap()
+                };
+                let (rduri, state) = (param("redirect_uri"), param("state"));
+                let injected = Uri::from_str(&format!("{}?code=injected&state=wrong", rduri));
+                let legit = Uri::from_str(&format!("{}?code=legitcode&state={}", rduri, state));
+                let client = self.0.clone();
+                let rejected = self.1.clone();
+                Box::new(
+                    self.0
+                        .get(injected.unwrap())
+                        .and_then(|response| {
+                            let status = response.status();
+                            response.into_body().concat2().map(move |body| {
+                                (status, String::from_utf8_lossy(&body).into_owned())
+                            })
+                        })
+                        .and_then(move |response| {
+                            *rejected.lock().unwrap() = Some(response);
+                            client.get(legit.unwrap())
+                        })
+                        .map(|_| None)
+                        .map_err(|e| Box::new(e) as Box<dyn Error + Send>),
+                )
+            }
+        }
+
+        let mut app_secret = parse_application_secret(r#"{"installed":{"client_id":"902216714886-k2v9uei3p1dk6h686jbsn9mo96tnbvto.apps.googleusercontent.com","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","client_secret":"iuMPN6Ne1PD7cos29Tk9rlqH","redirect_uris":["http://localhost"]}}"#).unwrap();
+        app_secret.token_uri = format!("{}/token", mockito::server_url());
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let rejected = Arc::new(Mutex::new(None));
+        let fd = FD(
+            hyper::Client::builder().keep_alive(false).build_http(),
+            rejected.clone(),
+        );
+        let mut inf =
+            InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral)
+                .delegate(fd)
+                .build_token_getter(client);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Only the code carrying the right state is exchanged.
+        let _m = mock("POST", "/token")
+            .match_body(mockito::Matcher::Regex("^code=legitcode&".to_string()))
+            .with_body(
+                r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        let token = rt.block_on(inf.token(vec!["https://googleapis.com/some/scope"]));
+
+        let (status, body) = rejected.lock().unwrap().take().unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("Invalid state parameter"), "{}", body);
+        assert_eq!(token.unwrap().access_token, "accesstoken");
+        _m.assert();
+    }
+
     #[test]
     fn test_request_url_builder() {
         assert_eq!(
@@ -738,15 +1026,49 @@ mod tests {
                 "812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5am\
                  rf.apps.googleusercontent.com",
                 vec![&"email".to_string(), &"profile".to_string()],
+                &Provider::google().auth_params,
+                GOOGLE_OOB_REDIRECT_URI,
+                None,
                 None
             )
         );
     }
 
+    #[test]
+    fn test_request_url_builder_pkce() {
+        let url = build_authentication_request_url(
+            "https://accounts.google.com/o/oauth2/auth",
+            "812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5amrf.apps.googleusercontent.com",
+            vec![&"email".to_string()],
+            &Provider::google().auth_params,
+            GOOGLE_OOB_REDIRECT_URI,
+            Some("xyz"),
+            Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"),
+        );
+        assert!(url.ends_with(
+            "&state=xyz&code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM\
+             &code_challenge_method=S256"
+        ));
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        let pkce =
+            PkceChallenge::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWrOTjXk".to_string());
+        assert_eq!(
+            pkce.challenge,
+            "IMA1ci1FXWzN-vlwQe23wLX3a1TyogT_txx_IeiIXbg"
+        );
+
+        let pkce = PkceChallenge::new().unwrap();
+        assert_eq!(pkce.verifier.len(), 43);
+        assert_ne!(pkce.verifier, PkceChallenge::new().unwrap().verifier);
+    }
+
     #[test]
     fn test_server_random_local_port() {
-        let addr1 = InstalledFlowServer::new(0).unwrap();
-        let addr2 = InstalledFlowServer::new(0).unwrap();
+        let addr1 = InstalledFlowServer::new(0, "state".to_string()).unwrap();
+        let addr2 = InstalledFlowServer::new(0, "state".to_string()).unwrap();
         assert_ne!(addr1.port, addr2.port);
     }
 
@@ -755,10 +1077,20 @@ mod tests {
         let (tx, rx) = oneshot::channel();
         let mut handler = InstalledFlowService {
             auth_code_tx: Arc::new(Mutex::new(Option::Some(tx))),
+            state: Arc::new("xyz".to_string()),
         };
+        // Codes without the matching state are rejected.
+        let url: Uri = "http://example.com:1234/?code=injected".parse().unwrap();
+        assert!(handler.handle_url(url).is_err());
+        let url: Uri = "http://example.com:1234/?code=injected&state=abc"
+            .parse()
+            .unwrap();
+        assert!(handler.handle_url(url).is_err());
         // URLs are usually a bit botched
-        let url: Uri = "http://example.com:1234/?code=ab/c%2Fd#".parse().unwrap();
-        handler.handle_url(url);
+        let url: Uri = "http://example.com:1234/?state=xyz&code=ab/c%2Fd#"
+            .parse()
+            .unwrap();
+        assert!(handler.handle_url(url).is_ok());
         assert_eq!(rx.wait().unwrap(), "ab/c/d".to_string());
     }
 
@@ -769,7 +1101,7 @@ mod tests {
             hyper::Client::builder()
                 .executor(runtime.executor())
                 .build_http();
-        let mut server = InstalledFlowServer::new(0).unwrap();
+        let mut server = InstalledFlowServer::new(0, "xyz".to_string()).unwrap();
 
         let response = client
             .get(
@@ -789,7 +1121,23 @@ mod tests {
 
         let response = client
             .get(
-                format!("http://127.0.0.1:{}/?code=ab/c%2Fd#", server.port)
+                format!("http://127.0.0.1:{}/?code=injected&state=abc", server.port)
+                    .parse()
+                    .unwrap(),
+            )
+            .wait();
+        match response {
+            Result::Ok(response) => {
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }
+            Result::Err(err) => {
+                panic!("Failed to request from local server: {:?}", err);
+            }
+        }
+
+        let response = client
+            .get(
+                format!("http://127.0.0.1:{}/?code=ab/c%2Fd&state=xyz#", server.port)
                     .parse()
                     .unwrap(),
             )
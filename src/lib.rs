@@ -5,10 +5,31 @@
 //! [following this guide](https://developers.google.com/youtube/registering_an_application) (for
 //! Google services) respectively the documentation of the API provider you want to connect to.
 //!
+//! # Providers
+//! The flows default to Google's endpoints and quirks. `Provider` describes other providers;
+//! there are presets for Google, the Microsoft identity platform, GitHub, Okta and Keycloak.
+//! Pass it to `InstalledFlow::provider()` or `DeviceFlow::provider()`, and use
+//! `Provider::application_secret()` to build the `ApplicationSecret` for the other flows.
+//!
+//! Instead of configuring a `Provider` by hand, `Discovery` fetches the metadata an
+//! authorization server publishes below its issuer URL (RFC 8414 or OpenID Connect Discovery),
+//! and builds the `Provider` from it.
+//!
 //! # Device Flow Usage
-//! As the `DeviceFlow` involves polling, the `DeviceFlowHelper` should be used
-//! as means to adhere to the protocol, and remain resilient to all kinds of errors
-//! that can occur on the way.
+//! The `DeviceFlow` implements the device authorization grant
+//! ([RFC 8628](https://tools.ietf.org/html/rfc8628)). It presents a code to the user through its
+//! `FlowDelegate`, and polls until the user has authorized the application, honoring the
+//! server's `slow_down` requests, or the code has expired.
+//!
+//! # Client credentials flow
+//! The `ClientCredentialsFlow` implements the client credentials grant, the usual
+//! machine-to-machine grant of providers such as Auth0, Okta, Keycloak and Azure AD. Like the
+//! other flows, it is used with an `Authenticator`.
+//!
+//! # Password flow
+//! The `PasswordFlow` implements the resource owner password credentials grant for identity
+//! providers that support nothing else. It asks its `FlowDelegate` for the credentials
+//! (`resource_owner_credentials()`) whenever it needs them, so they are never stored.
 //!
 //! # Service account "flow"
 //! When using service account credentials, no user interaction is required. The access token
@@ -19,6 +40,44 @@
 //! for a detailed description of the protocol. This crate implements OAuth for Service Accounts
 //! based on the Google APIs; it may or may not work with other providers.
 //!
+//! With `ServiceAccountAccess::self_signed_jwt()`, the signed JWT is used as access token
+//! directly, without a round-trip to the token endpoint.
+//!
+//! `ServiceAccountAccess` and `MetadataAccess` also implement `GetIdToken`, yielding ID tokens
+//! for a target audience, as required e.g. by Cloud Run, Cloud Functions and IAP.
+//!
+//! # Service account impersonation
+//! `ImpersonatedAccess` exchanges the token of any base credential (e.g. `ServiceAccountAccess`
+//! or `MetadataAccess`) for a token of another service account, using the IAM Credentials API.
+//!
+//! # Workload Identity Federation
+//! `ExternalAccountAccess` uses `external_account` credential files (see
+//! `external_account_secret_from_file()`): It exchanges a token of another identity provider,
+//! read from a file, a URL or an executable, for a Google access token, and optionally
+//! impersonates a service account with it.
+//!
+//! # Token exchange
+//! `TokenExchange` implements the OAuth 2.0 token exchange grant (RFC 8693), e.g. to obtain a
+//! token for a downstream service from an incoming one. `TokenExchange::build_with_source()`
+//! yields a token source exchanging the tokens of another `GetToken`.
+//!
+//! # Authorized user credentials
+//! `AuthorizedUserAccess` uses the credentials file written by
+//! `gcloud auth application-default login` (see `authorized_user_secret_from_file()`), which
+//! contains a refresh token, to obtain access tokens.
+//!
+//! # Application Default Credentials
+//! `ApplicationDefaultCredentials` finds credentials the way Google's client libraries do: It
+//! uses the file named by `GOOGLE_APPLICATION_CREDENTIALS` (service account, authorized user or
+//! external account credentials), the file written by
+//! `gcloud auth application-default login`, or the GCE metadata server, and yields a matching
+//! token source.
+//!
+//! # OpenID Connect
+//! If the `openid` scope is requested, the flows return an ID token along with the access token.
+//! `Token::id_token_claims()` decodes it, e.g. to show who is logged in. `JwtVerifier` verifies
+//! the signature of ID tokens (or other JWTs) against the issuer's JSON Web Key Set.
+//!
 //! # Installed Flow Usage
 //! The `InstalledFlow` involves showing a URL to the user (or opening it in a browser)
 //! and then either prompting the user to enter a displayed code, or make the authorizing
@@ -83,28 +142,70 @@
 #[macro_use]
 extern crate serde_derive;
 
+mod application_default;
 mod authenticator;
 mod authenticator_delegate;
+mod authorized_user;
+mod client_credentials;
 mod device;
+mod discovery;
+mod encryption;
+mod external_account;
 mod helper;
+mod id_token;
+mod impersonated;
 mod installed;
 mod metadata;
+mod password;
+mod provider;
 mod refresh;
+mod revoke;
 mod service_account;
 mod storage;
+mod token_exchange;
 mod types;
+mod verifier;
 
+pub use crate::application_default::ApplicationDefaultCredentials;
 pub use crate::authenticator::{AuthFlow, Authenticator};
 pub use crate::authenticator_delegate::{
     AuthenticatorDelegate, DefaultAuthenticatorDelegate, DefaultFlowDelegate, FlowDelegate,
-    PollInformation,
+    PollInformation, ResourceOwnerCredentials,
+};
+pub use crate::authorized_user::{AuthorizedUserAccess, AuthorizedUserSecret};
+pub use crate::client_credentials::{
+    ClientAuthMethod, ClientCredentialsFlow, ClientCredentialsFlowImpl,
 };
 pub use crate::device::{DeviceFlow, GOOGLE_DEVICE_CODE_URL};
+pub use crate::discovery::{Discovery, DiscoveryClient, ServerMetadata, WellKnown};
+pub use crate::encryption::{DecryptionError, EncryptionKeys};
+pub use crate::external_account::{
+    CredentialSource, CredentialSourceFormat, ExecutableSource, ExternalAccountAccess,
+    ExternalAccountSecret, ServiceAccountImpersonation, GOOGLE_STS_URL,
+};
 pub use crate::helper::*;
+pub use crate::id_token::IdTokenClaims;
+pub use crate::impersonated::{ImpersonatedAccess, IAM_CREDENTIALS_URL};
 pub use crate::installed::{InstalledFlow, InstalledFlowReturnMethod};
+pub use crate::metadata::MetadataAccess;
+pub use crate::password::{PasswordFlow, PasswordFlowImpl};
+pub use crate::provider::{
+    Provider, DEVICE_CODE_GRANT_TYPE, GOOGLE_OOB_REDIRECT_URI, LEGACY_DEVICE_GRANT_TYPE,
+};
+pub use crate::revoke::{RevokeFlow, TokenTypeHint, GOOGLE_REVOKE_URL};
 pub use crate::service_account::*;
-pub use crate::storage::{DiskTokenStorage, MemoryStorage, NullStorage, TokenStorage};
+pub use crate::storage::{
+    AsyncTokenStorage, DiskTokenStorage, MemoryStorage, NullStorage, TokenStorage,
+};
+pub use crate::token_exchange::{
+    ExchangedToken, TokenExchange, TokenExchangeClient, ACCESS_TOKEN_TYPE, ID_TOKEN_TYPE,
+    JWT_TOKEN_TYPE, REFRESH_TOKEN_TYPE, SAML1_TOKEN_TYPE, SAML2_TOKEN_TYPE,
+    TOKEN_EXCHANGE_GRANT_TYPE,
+};
 pub use crate::types::{
-    ApplicationSecret, ConsoleApplicationSecret, FlowType, GetToken, PollError, RefreshResult,
-    RequestError, Scheme, Token, TokenType,
+    ApplicationSecret, BoxedTokenSource, ConsoleApplicationSecret, FlowType, GetIdToken, GetToken,
+    PollError, RefreshResult, RequestError, Scheme, Token, TokenType,
+};
+pub use crate::verifier::{
+    Jwk, Jwks, JwtVerifier, VerificationError, GOOGLE_CERTS_URL, GOOGLE_ISSUERS,
 };
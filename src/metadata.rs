@@ -3,6 +3,7 @@
 // Refer to the project root for licensing information.
 //
 use std::convert::AsRef;
+use std::env;
 use std::sync::{Arc, Mutex};
 
 use futures::prelude::*;
@@ -14,12 +15,55 @@ use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
 
 use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
-use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+use crate::id_token::IdTokenCache;
+use crate::types::{ApplicationSecret, GetIdToken, GetToken, RequestError, Token};
 
-fn build_token_request() -> hyper::Request<hyper::Body> {
+/// The metadata server of Google Compute Engine.
+const METADATA_URL: &str = "http://metadata.google.internal";
+/// The metadata server addressed by IP, which avoids slow DNS lookups outside of GCE.
+pub(crate) const METADATA_IP_URL: &str = "http://169.254.169.254";
+
+/// Overrides the host (and port) of the metadata server, e.g. for an emulator.
+const METADATA_HOST_ENV_VAR: &str = "GCE_METADATA_HOST";
+
+/// The value of `GCE_METADATA_HOST`, if set.
+pub(crate) fn metadata_host_from_env() -> Option<String> {
+    env::var(METADATA_HOST_ENV_VAR).ok()
+}
+
+/// The URL of the metadata server, given the value of `GCE_METADATA_HOST`; `default` if it is
+/// not set.
+pub(crate) fn metadata_url(env_host: Option<String>, default: &str) -> String {
+    match env_host {
+        Some(ref host) if !host.is_empty() => format!("http://{}", host),
+        _ => default.to_string(),
+    }
+}
+
+fn build_token_request(metadata_url: &str) -> hyper::Request<hyper::Body> {
+    let mut builder = hyper::Request::builder();
+    let uri = format!(
+        "{}/computeMetadata/v1/instance/service-accounts/default/token",
+        metadata_url
+    );
+    builder
+        .uri(uri)
+        .header("Metadata-Flavor", "Google")
+        .method("GET");
+    builder.body(hyper::Body::empty()).unwrap()
+}
+
+fn build_identity_request(metadata_url: &str, audience: &str) -> hyper::Request<hyper::Body> {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(&[("audience", audience), ("format", "full")])
+        .finish();
     let mut builder = hyper::Request::builder();
-    let uri = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token".to_string();
-    builder.uri(uri)
+    let uri = format!(
+        "{}/computeMetadata/v1/instance/service-accounts/default/identity?{}",
+        metadata_url, query
+    );
+    builder
+        .uri(uri)
         .header("Metadata-Flavor", "Google")
         .method("GET");
     builder.body(hyper::Body::empty()).unwrap()
@@ -29,55 +73,86 @@ fn build_token_request() -> hyper::Request<hyper::Body> {
 struct JSONTokenResponse {
     access_token: String,
     expires_in: i64,
-    token_type: String
+    token_type: String,
 }
 
 pub struct MetadataAccess<C> {
-    client: C
+    client: C,
+    metadata_url: Option<String>,
 }
 
 impl MetadataAccess<DefaultHyperClient> {
     pub fn new() -> Self {
-        Self { client: DefaultHyperClient }
+        Self {
+            client: DefaultHyperClient,
+            metadata_url: None,
+        }
+    }
+}
+
+impl Default for MetadataAccess<DefaultHyperClient> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<C> MetadataAccess<C>
 where
     C: HyperClientBuilder,
-    C::Connector: 'static
+    C::Connector: 'static,
 {
     pub fn hyper_client<NewC: HyperClientBuilder>(
         self,
-        hyper_client: NewC
+        hyper_client: NewC,
     ) -> MetadataAccess<NewC> {
         MetadataAccess {
-            client: hyper_client
+            client: hyper_client,
+            metadata_url: self.metadata_url,
+        }
+    }
+
+    /// Use the metadata server at `url`, e.g. `http://localhost:8080`. The default is
+    /// `http://metadata.google.internal`, or the host given in the `GCE_METADATA_HOST`
+    /// environment variable.
+    pub fn metadata_url<S: Into<String>>(self, url: S) -> Self {
+        MetadataAccess {
+            metadata_url: Some(url.into()),
+            ..self
         }
     }
-    pub fn build(self) -> impl GetToken {
-        MetadataAccessImpl::new(self.client.build_hyper_client())
+
+    /// Build the configured MetadataAccess. Besides access tokens, it yields ID tokens for a
+    /// target audience (see `GetIdToken`).
+    pub fn build(self) -> impl GetToken + GetIdToken {
+        let metadata_url = self
+            .metadata_url
+            .unwrap_or_else(|| metadata_url(metadata_host_from_env(), METADATA_URL));
+        MetadataAccessImpl::new(self.client.build_hyper_client(), metadata_url)
     }
 }
 
 pub struct MetadataAccessImpl<C> {
-    client: hyper::client::Client<C, hyper::Body>
+    client: hyper::client::Client<C, hyper::Body>,
+    metadata_url: String,
+    id_token_cache: IdTokenCache,
 }
 
 impl<C> MetadataAccessImpl<C>
 where
-    C: hyper::client::connect::Connect
+    C: hyper::client::connect::Connect,
 {
-    fn new(client: hyper::Client<C>) -> Self {
+    fn new(client: hyper::Client<C>, metadata_url: String) -> Self {
         MetadataAccessImpl {
-            client
+            client,
+            metadata_url,
+            id_token_cache: IdTokenCache::default(),
         }
     }
 }
 
 impl<C: 'static> GetToken for MetadataAccessImpl<C>
 where
-    C: hyper::client::connect::Connect
+    C: hyper::client::connect::Connect,
 {
     fn token<I, T>(
         &mut self,
@@ -87,12 +162,14 @@ where
         T: Into<String>,
         I: IntoIterator<Item = T>,
     {
-        let op = self.client.request(build_token_request())
+        let op = self
+            .client
+            .request(build_token_request(&self.metadata_url))
             .and_then(move |r| {
                 r.into_body()
                     .concat2()
                     .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                        // TODO: error handling
+                // TODO: error handling
             })
             .then(|body_or| {
                 let resp = match body_or {
@@ -107,7 +184,7 @@ where
                     Err(e) => {
                         return Err(RequestError::JSONError(e));
                     }
-                    Ok(tok) => { Ok(tok) }
+                    Ok(tok) => Ok(tok),
                 }
             })
             .and_then(|tokens| {
@@ -117,6 +194,8 @@ where
                     token_type: tokens.token_type,
                     expires_in: Some(tokens.expires_in),
                     expires_in_timestamp: None,
+                    scope: None,
+                    id_token: None,
                 };
 
                 token.set_expiry_absolute();
@@ -136,6 +215,47 @@ where
     }
 }
 
+impl<C: 'static> GetIdToken for MetadataAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn id_token<A>(
+        &mut self,
+        target_audience: A,
+    ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
+    where
+        A: Into<String>,
+    {
+        let target_audience = target_audience.into();
+        if let Some(token) = self.id_token_cache.get(&target_audience) {
+            return Box::new(futures::future::ok(token));
+        }
+        let cache = self.id_token_cache.clone();
+        let op = self
+            .client
+            .request(build_identity_request(&self.metadata_url, &target_audience))
+            .map_err(RequestError::ClientError)
+            .and_then(|r| {
+                let status = r.status();
+                r.into_body()
+                    .concat2()
+                    .map_err(RequestError::ClientError)
+                    .map(move |body| (status, String::from_utf8_lossy(&body).into_owned()))
+            })
+            .and_then(move |(status, body)| {
+                if !status.is_success() {
+                    return Err(RequestError::BadServerResponse(format!(
+                        "identity request failed with status {}: {}",
+                        status, body
+                    )));
+                }
+                // The response is the bare JWT.
+                cache.set(target_audience, body.trim().to_string())
+            });
+        Box::new(op)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,11 +263,74 @@ mod tests {
     #[test]
     fn test_metadata_e2e() {
         let mut auth = MetadataAccess::new().build();
-        let tok = auth.token(vec!["https://www.googleapis.com/auth/drive.file".to_string()]);
+        let tok = auth.token(vec![
+            "https://www.googleapis.com/auth/drive.file".to_string()
+        ]);
         let fut = tok.map_err(|e| println!("error: {:?}", e)).and_then(|t| {
             println!("The token is {:?}", t);
             Ok(())
         });
         tokio::run(fut);
     }
+
+    #[test]
+    fn test_metadata_url() {
+        assert_eq!(
+            metadata_url(None, METADATA_URL),
+            "http://metadata.google.internal"
+        );
+        assert_eq!(
+            metadata_url(Some(String::new()), METADATA_IP_URL),
+            "http://169.254.169.254"
+        );
+        assert_eq!(
+            metadata_url(Some("localhost:8080".to_string()), METADATA_URL),
+            "http://localhost:8080"
+        );
+    }
+
+    #[test]
+    fn test_id_token() {
+        let claims = format!(
+            r#"{{"iss": "https://accounts.google.com", "sub": "1234", "aud": "https://service", "exp": {}, "iat": 0}}"#,
+            chrono::Utc::now().timestamp() + 3600
+        );
+        let id_token = format!(
+            "{}.{}.c2lnbmF0dXJl",
+            base64::encode_config(r#"{"alg":"RS256","typ":"JWT"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&claims, base64::URL_SAFE_NO_PAD)
+        );
+        let _m = mockito::mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fservice&format=full",
+        )
+        .match_header("Metadata-Flavor", "Google")
+        .with_status(200)
+        .with_body(&id_token)
+        .expect(1)
+        .create();
+
+        let mut access = MetadataAccess::new()
+            .metadata_url(mockito::server_url())
+            .build();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        // The second request is served from the cache.
+        for _ in 0..2 {
+            let tok = rt.block_on(access.id_token("https://service")).unwrap();
+            assert_eq!(tok, id_token);
+        }
+        _m.assert();
+
+        let _m = mockito::mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fother&format=full",
+        )
+        .with_status(404)
+        .with_body("not found")
+        .create();
+        match rt.block_on(access.id_token("https://other")) {
+            Err(RequestError::BadServerResponse(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
 }
@@ -0,0 +1,242 @@
+//! This module implements the OAuth 2.0 resource owner password credentials grant
+//! ([RFC 6749, section 4.3](https://tools.ietf.org/html/rfc6749#section-4.3)): The user's
+//! username and password are sent to the token endpoint directly. Only use it with identity
+//! providers that support nothing else; the credentials are obtained from the `FlowDelegate`
+//! for every token request and never stored.
+//!
+//! Used with an `Authenticator`, tokens are stored in its `TokenStorage` and refreshed using their
+//! refresh token, so the credentials are only needed again once the refresh token stops working.
+
+use crate::authenticator_delegate::FlowDelegate;
+use crate::client_credentials::{post_token_request, ClientAuthMethod};
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+use futures::prelude::*;
+use url::form_urlencoded;
+
+/// Obtains tokens using the resource owner password credentials grant. The credentials are
+/// provided by the delegate's `resource_owner_credentials()`.
+///
+/// ```no_run
+/// use futures::{future, prelude::*};
+/// use std::error::Error;
+/// use yup_oauth2::{
+///     ApplicationSecret, Authenticator, FlowDelegate, GetToken, PasswordFlow,
+///     ResourceOwnerCredentials,
+/// };
+///
+/// #[derive(Clone)]
+/// struct EnvCredentials;
+/// impl FlowDelegate for EnvCredentials {
+///     fn resource_owner_credentials(
+///         &mut self,
+///     ) -> Box<dyn Future<Item = ResourceOwnerCredentials, Error = Box<dyn Error + Send>> + Send>
+///     {
+///         Box::new(future::ok(ResourceOwnerCredentials {
+///             username: std::env::var("IDP_USER").unwrap(),
+///             password: std::env::var("IDP_PASSWORD").unwrap(),
+///         }))
+///     }
+/// }
+///
+/// let secret = ApplicationSecret {
+///     client_id: "tooling".to_string(),
+///     token_uri: "https://idp.example.com/oauth/token".to_string(),
+///     ..Default::default()
+/// };
+/// let mut auth = Authenticator::new(PasswordFlow::new(secret, EnvCredentials))
+///     .persist_tokens_to_disk("tokencache.json")
+///     .build()
+///     .unwrap();
+/// let fut = auth
+///     .token(vec!["deploy"])
+///     .map(|token| println!("The token is {:?}", token))
+///     .map_err(|e| println!("error: {:?}", e));
+/// tokio::run(fut);
+/// ```
+#[derive(Clone)]
+pub struct PasswordFlow<FD> {
+    application_secret: ApplicationSecret,
+    flow_delegate: FD,
+    auth_method: ClientAuthMethod,
+}
+
+impl<FD: FlowDelegate> PasswordFlow<FD> {
+    /// Create a new PasswordFlow obtaining the credentials from `delegate`. Only `client_id`,
+    /// `client_secret` and `token_uri` of the secret are used. The client is authenticated
+    /// by sending its ID and secret as form parameters by default, as `RefreshFlow` does.
+    pub fn new(secret: ApplicationSecret, delegate: FD) -> PasswordFlow<FD> {
+        PasswordFlow {
+            application_secret: secret,
+            flow_delegate: delegate,
+            auth_method: ClientAuthMethod::ClientSecretPost,
+        }
+    }
+
+    /// Use the provided client authentication method.
+    pub fn auth_method(self, method: ClientAuthMethod) -> Self {
+        PasswordFlow {
+            auth_method: method,
+            ..self
+        }
+    }
+}
+
+impl<FD, C> crate::authenticator::AuthFlow<C> for PasswordFlow<FD>
+where
+    FD: FlowDelegate + Send + 'static,
+    C: hyper::client::connect::Connect + 'static,
+{
+    type TokenGetter = PasswordFlowImpl<FD, C>;
+
+    fn build_token_getter(self, client: hyper::Client<C>) -> Self::TokenGetter {
+        PasswordFlowImpl {
+            client,
+            application_secret: self.application_secret,
+            fd: self.flow_delegate,
+            auth_method: self.auth_method,
+        }
+    }
+}
+
+/// The PasswordFlow implementation.
+pub struct PasswordFlowImpl<FD, C> {
+    client: hyper::Client<C, hyper::Body>,
+    application_secret: ApplicationSecret,
+    fd: FD,
+    auth_method: ClientAuthMethod,
+}
+
+impl<FD, C> GetToken for PasswordFlowImpl<FD, C>
+where
+    FD: FlowDelegate + Send + 'static,
+    C: hyper::client::connect::Connect + 'static,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        let client = self.client.clone();
+        let secret = self.application_secret.clone();
+        let auth_method = self.auth_method;
+        Box::new(
+            self.fd
+                .resource_owner_credentials()
+                .map_err(|e| {
+                    RequestError::UserError(format!("could not obtain credentials: {}", e))
+                })
+                .and_then(move |credentials| {
+                    let mut form = form_urlencoded::Serializer::new(String::new());
+                    form.append_pair("grant_type", "password")
+                        .append_pair("username", &credentials.username)
+                        .append_pair("password", &credentials.password);
+                    if !scopes.is_empty() {
+                        form.append_pair("scope", &scopes.join(" "));
+                    }
+                    post_token_request(&client, &secret, auth_method, form)
+                }),
+        )
+    }
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+    fn application_secret(&self) -> ApplicationSecret {
+        self.application_secret.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authenticator::Authenticator;
+    use crate::authenticator_delegate::{DefaultFlowDelegate, ResourceOwnerCredentials};
+
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::future;
+    use hyper_rustls::HttpsConnector;
+
+    /// Hands out fixed credentials and counts how often they were asked for.
+    #[derive(Clone)]
+    struct FD(Arc<AtomicUsize>);
+
+    impl FlowDelegate for FD {
+        fn resource_owner_credentials(
+            &mut self,
+        ) -> Box<dyn Future<Item = ResourceOwnerCredentials, Error = Box<dyn Error + Send>> + Send>
+        {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::ok(ResourceOwnerCredentials {
+                username: "alice".to_string(),
+                password: "p@ss word".to_string(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_password_end2end() {
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let secret = ApplicationSecret {
+            client_id: "tooling".to_string(),
+            client_secret: "secret".to_string(),
+            token_uri: format!("{}/token", mockito::server_url()),
+            ..Default::default()
+        };
+
+        // The token is obtained with the delegate's credentials, stored, and refreshed without
+        // asking for the credentials again.
+        {
+            let asked = Arc::new(AtomicUsize::new(0));
+            let _password = mockito::mock("POST", "/token")
+                .match_body(
+                    "grant_type=password&username=alice&password=p%40ss+word&scope=deploy\
+                     &client_id=tooling&client_secret=secret",
+                )
+                .with_status(200)
+                .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 0}"#)
+                .expect(1)
+                .create();
+            let _refresh = mockito::mock("POST", "/token")
+                .match_body(mockito::Matcher::Regex(
+                    ".*grant_type=refresh_token.*".to_string(),
+                ))
+                .with_status(200)
+                .with_body(r#"{"access_token": "refreshedtoken", "token_type": "Bearer", "expires_in": 3600}"#)
+                .expect(1)
+                .create();
+            let mut auth = Authenticator::new(PasswordFlow::new(secret.clone(), FD(asked.clone())))
+                .hyper_client(client.clone())
+                .build()
+                .unwrap();
+            let token = rt.block_on(auth.token(vec!["deploy"])).unwrap();
+            assert_eq!(token.access_token, "accesstoken");
+            let token = rt.block_on(auth.token(vec!["deploy"])).unwrap();
+            assert_eq!(token.access_token, "refreshedtoken");
+            assert_eq!(token.refresh_token.as_deref(), Some("refreshtoken"));
+            assert_eq!(asked.load(Ordering::SeqCst), 1);
+            _password.assert();
+            _refresh.assert();
+        }
+        // Without a delegate providing credentials, the flow fails.
+        {
+            let mut flow = crate::authenticator::AuthFlow::build_token_getter(
+                PasswordFlow::new(secret, DefaultFlowDelegate),
+                client,
+            );
+            match rt.block_on(flow.token(vec!["deploy"])) {
+                Err(RequestError::UserError(_)) => {}
+                r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+            }
+        }
+    }
+}
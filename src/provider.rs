@@ -0,0 +1,261 @@
+//! This module describes OAuth 2.0 providers (authorization servers): their endpoints and the
+//! quirks the flows have to take into account. `Provider` has presets for common providers; the
+//! `InstalledFlow` and the `DeviceFlow` default to Google, and take a `Provider` to talk to any
+//! other one. Providers publishing their metadata can be discovered using `Discovery`.
+
+use crate::types::ApplicationSecret;
+
+/// The `grant_type` of device access token requests according to
+/// [RFC 8628](https://tools.ietf.org/html/rfc8628#section-3.4).
+pub const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// The pre-standard `grant_type` of device access token requests, for servers predating RFC 8628.
+pub const LEGACY_DEVICE_GRANT_TYPE: &str = "http://oauth.net/grant_type/device/1.0";
+/// The redirect URI asking Google to display the authorization code to the user, who then copies
+/// it into the application.
+pub const GOOGLE_OOB_REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+
+/// The endpoints and quirks of an OAuth 2.0 provider.
+///
+/// ```
+/// use yup_oauth2::{InstalledFlow, InstalledFlowReturnMethod, Provider};
+///
+/// let provider = Provider::keycloak("https://sso.example.com", "internal");
+/// let secret = provider.application_secret("cli".to_string(), String::new());
+/// let flow = InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral)
+///     .provider(provider);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    /// The authorization endpoint, if the provider supports the authorization code grant.
+    pub auth_uri: Option<String>,
+    /// The token endpoint.
+    pub token_uri: String,
+    /// The device authorization endpoint, if the provider supports the device flow.
+    pub device_authorization_uri: Option<String>,
+    /// The token revocation endpoint, if the provider supports revocation.
+    pub revocation_uri: Option<String>,
+    /// The token introspection endpoint ([RFC 7662](https://tools.ietf.org/html/rfc7662)), if
+    /// known.
+    pub introspection_uri: Option<String>,
+    /// The OpenID Connect UserInfo endpoint, if known.
+    pub userinfo_uri: Option<String>,
+    /// The URL of the JWKS the provider signs ID tokens with, if known; see
+    /// `JwtVerifier::certs_url()`.
+    pub jwks_uri: Option<String>,
+    /// The redirect URI making the provider display the authorization code to the user, as used
+    /// by `InstalledFlowReturnMethod::Interactive`; `None` if the provider has no such page.
+    pub oob_redirect_uri: Option<String>,
+    /// Additional parameters for authorization requests, e.g. Google's `access_type=offline`,
+    /// without which it doesn't issue refresh tokens.
+    pub auth_params: Vec<(String, String)>,
+    /// The `grant_type` of device access token requests.
+    pub device_grant_type: String,
+    /// Whether the provider accepts PKCE parameters.
+    pub pkce: bool,
+}
+
+impl Provider {
+    /// Create a standards-compliant provider with the given authorization and token endpoints.
+    pub fn new(auth_uri: String, token_uri: String) -> Provider {
+        Provider {
+            auth_uri: Some(auth_uri),
+            token_uri,
+            device_authorization_uri: None,
+            revocation_uri: None,
+            introspection_uri: None,
+            userinfo_uri: None,
+            jwks_uri: None,
+            oob_redirect_uri: None,
+            auth_params: Vec::new(),
+            device_grant_type: DEVICE_CODE_GRANT_TYPE.to_string(),
+            pkce: true,
+        }
+    }
+
+    /// Google's OAuth 2.0 endpoints.
+    pub fn google() -> Provider {
+        Provider {
+            device_authorization_uri: Some(crate::device::GOOGLE_DEVICE_CODE_URL.to_string()),
+            revocation_uri: Some(crate::revoke::GOOGLE_REVOKE_URL.to_string()),
+            userinfo_uri: Some("https://openidconnect.googleapis.com/v1/userinfo".to_string()),
+            jwks_uri: Some(crate::verifier::GOOGLE_CERTS_URL.to_string()),
+            oob_redirect_uri: Some(GOOGLE_OOB_REDIRECT_URI.to_string()),
+            auth_params: vec![("access_type".to_string(), "offline".to_string())],
+            ..Provider::new(
+                "https://accounts.google.com/o/oauth2/auth".to_string(),
+                "https://oauth2.googleapis.com/token".to_string(),
+            )
+        }
+    }
+
+    /// The Microsoft identity platform (Azure AD v2.0 endpoints). `tenant` is a tenant ID or
+    /// domain, or one of `common`, `organizations` and `consumers`. Request the
+    /// `offline_access` scope to obtain refresh tokens.
+    pub fn microsoft(tenant: &str) -> Provider {
+        let base = format!("https://login.microsoftonline.com/{}/oauth2/v2.0", tenant);
+        Provider {
+            device_authorization_uri: Some(format!("{}/devicecode", base)),
+            userinfo_uri: Some("https://graph.microsoft.com/oidc/userinfo".to_string()),
+            jwks_uri: Some(format!(
+                "https://login.microsoftonline.com/{}/discovery/v2.0/keys",
+                tenant
+            )),
+            ..Provider::new(format!("{}/authorize", base), format!("{}/token", base))
+        }
+    }
+
+    /// GitHub (OAuth apps). GitHub doesn't support PKCE or token revocation through OAuth, and
+    /// only responds with JSON because the flows send `Accept: application/json`.
+    pub fn github() -> Provider {
+        Provider {
+            device_authorization_uri: Some("https://github.com/login/device/code".to_string()),
+            pkce: false,
+            ..Provider::new(
+                "https://github.com/login/oauth/authorize".to_string(),
+                "https://github.com/login/oauth/access_token".to_string(),
+            )
+        }
+    }
+
+    /// An Okta authorization server, identified by its issuer, e.g.
+    /// `https://dev-123456.okta.com/oauth2/default`. Request the `offline_access` scope to
+    /// obtain refresh tokens.
+    pub fn okta(issuer: &str) -> Provider {
+        let base = format!("{}/v1", issuer.trim_end_matches('/'));
+        Provider {
+            device_authorization_uri: Some(format!("{}/device/authorize", base)),
+            revocation_uri: Some(format!("{}/revoke", base)),
+            introspection_uri: Some(format!("{}/introspect", base)),
+            userinfo_uri: Some(format!("{}/userinfo", base)),
+            jwks_uri: Some(format!("{}/keys", base)),
+            ..Provider::new(format!("{}/authorize", base), format!("{}/token", base))
+        }
+    }
+
+    /// A Keycloak realm, e.g. `Provider::keycloak("https://sso.example.com", "internal")`. For
+    /// Keycloak versions before 17, `base_url` has to include the `/auth` path.
+    pub fn keycloak(base_url: &str, realm: &str) -> Provider {
+        let base = format!(
+            "{}/realms/{}/protocol/openid-connect",
+            base_url.trim_end_matches('/'),
+            realm
+        );
+        Provider {
+            device_authorization_uri: Some(format!("{}/auth/device", base)),
+            revocation_uri: Some(format!("{}/revoke", base)),
+            introspection_uri: Some(format!("{}/token/introspect", base)),
+            userinfo_uri: Some(format!("{}/userinfo", base)),
+            jwks_uri: Some(format!("{}/certs", base)),
+            ..Provider::new(format!("{}/auth", base), format!("{}/token", base))
+        }
+    }
+
+    /// Build an `ApplicationSecret` for this provider with the given client credentials. Use
+    /// an empty `client_secret` for public clients. The secret's `auth_uri` is empty if the
+    /// provider has no authorization endpoint.
+    pub fn application_secret(
+        &self,
+        client_id: String,
+        client_secret: String,
+    ) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id,
+            client_secret,
+            auth_uri: self.auth_uri.clone().unwrap_or_default(),
+            token_uri: self.token_uri.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for Provider {
+    fn default() -> Provider {
+        Provider::google()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authenticator::AuthFlow;
+    use crate::device::DeviceFlow;
+    use crate::installed::{InstalledFlow, InstalledFlowReturnMethod};
+    use crate::types::{GetToken, RequestError};
+
+    use futures::prelude::*;
+    use hyper_rustls::HttpsConnector;
+
+    #[test]
+    fn test_presets() {
+        let keycloak = Provider::keycloak("https://sso.example.com/", "internal");
+        assert_eq!(
+            keycloak.token_uri,
+            "https://sso.example.com/realms/internal/protocol/openid-connect/token"
+        );
+        assert_eq!(
+            keycloak.device_authorization_uri.as_deref(),
+            Some("https://sso.example.com/realms/internal/protocol/openid-connect/auth/device")
+        );
+        let okta = Provider::okta("https://dev-123456.okta.com/oauth2/default");
+        assert_eq!(
+            okta.auth_uri.as_deref(),
+            Some("https://dev-123456.okta.com/oauth2/default/v1/authorize")
+        );
+        assert_eq!(okta.device_grant_type, DEVICE_CODE_GRANT_TYPE);
+        assert_eq!(
+            Provider::microsoft("common").token_uri,
+            "https://login.microsoftonline.com/common/oauth2/v2.0/token"
+        );
+        assert!(!Provider::github().pkce);
+        assert_eq!(Provider::google().device_grant_type, DEVICE_CODE_GRANT_TYPE);
+
+        let secret = okta.application_secret("client".to_string(), String::new());
+        assert_eq!(secret.token_uri, okta.token_uri);
+        assert_eq!(Some(secret.auth_uri), okta.auth_uri);
+    }
+
+    #[test]
+    fn test_unsupported_by_provider() {
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let provider = Provider::new(
+            "https://auth.example.com/authorize".to_string(),
+            "https://auth.example.com/token".to_string(),
+        );
+        let secret = provider.application_secret("client".to_string(), String::new());
+
+        // Without an OOB redirect URI, the interactive installed flow can't work.
+        let mut flow = InstalledFlow::new(secret.clone(), InstalledFlowReturnMethod::Interactive)
+            .provider(provider.clone())
+            .build_token_getter(client.clone());
+        match flow.token(vec!["scope"]).wait() {
+            Err(RequestError::UserError(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+        }
+        // Without a device authorization endpoint, neither can the device flow.
+        let mut flow = DeviceFlow::new(secret.clone())
+            .provider(provider.clone())
+            .build_token_getter(client.clone());
+        match flow.token(vec!["scope"]).wait() {
+            Err(RequestError::UserError(ref e)) => {
+                assert_eq!(e, "the provider has no device authorization endpoint")
+            }
+            r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+        }
+        // Without an authorization endpoint, no installed flow can.
+        let provider = Provider {
+            auth_uri: None,
+            ..provider
+        };
+        let mut flow = InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral)
+            .provider(provider)
+            .build_token_getter(client);
+        match flow.token(vec!["scope"]).wait() {
+            Err(RequestError::UserError(ref e)) => {
+                assert_eq!(e, "the provider has no authorization endpoint")
+            }
+            r => panic!("unexpected result {:?}", r.map(|t| t.access_token)),
+        }
+    }
+}
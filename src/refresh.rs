@@ -18,6 +18,8 @@ pub struct RefreshFlow;
 
 impl RefreshFlow {
     /// Attempt to refresh the given token, and obtain a new, valid one.
+    /// If the server rotates refresh tokens, the returned `Token` carries the new refresh token;
+    /// otherwise it carries `refresh_token` again.
     /// If the `RefreshResult` is `RefreshResult::Error`, you may retry within an interval
     /// of your choice. If it is `RefreshResult:RefreshError`, your refresh token is invalid
     /// or your authorization was revoked. Therefore no further attempt shall be made,
@@ -47,6 +49,7 @@ impl RefreshFlow {
 
         let request = hyper::Request::post(client_secret.token_uri.clone())
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
             .body(hyper::Body::from(req))
             .unwrap(); // TODO: error handling
 
@@ -75,6 +78,9 @@ impl RefreshFlow {
                     access_token: String,
                     token_type: String,
                     expires_in: i64,
+                    refresh_token: Option<String>,
+                    scope: Option<String>,
+                    id_token: Option<String>,
                 }
 
                 match json::from_str::<JsonError>(&json_str) {
@@ -88,12 +94,19 @@ impl RefreshFlow {
                 }
 
                 let t: JsonToken = json::from_str(&json_str).unwrap();
+                // Providers rotating refresh tokens return a new one and invalidate the old one.
+                let refresh_token = match t.refresh_token {
+                    Some(ref rt) if !rt.is_empty() => rt.clone(),
+                    _ => refresh_token,
+                };
                 Ok(RefreshResult::Success(Token {
                     access_token: t.access_token,
                     token_type: t.token_type,
-                    refresh_token: Some(refresh_token.to_string()),
+                    refresh_token: Some(refresh_token),
                     expires_in: None,
                     expires_in_timestamp: Some(Utc::now().timestamp() + t.expires_in),
+                    scope: t.scope,
+                    id_token: t.id_token,
                 }))
             })
             .map_err(RequestError::Refresh)
@@ -149,6 +162,8 @@ mod tests {
                     RefreshResult::Success(tok) => {
                         assert_eq!("new-access-token", tok.access_token);
                         assert_eq!("Bearer", tok.token_type);
+                        assert_eq!(Some("my-refresh-token".to_string()), tok.refresh_token);
+                        assert_eq!(None, tok.scope);
                     }
                     _ => panic!(format!("unexpected RefreshResult {:?}", rr)),
                 }
@@ -158,6 +173,30 @@ mod tests {
             rt.block_on(fut).expect("block_on");
             _m.assert();
         }
+        // Success, rotating the refresh token.
+        {
+            let _m = mockito::mock("POST", "/token")
+                .match_body(mockito::Matcher::Regex(
+                    ".*refresh_token=my-refresh-token.*".to_string(),
+                ))
+                .with_status(200)
+                .with_body(r#"{"access_token": "new-access-token", "token_type": "Bearer", "expires_in": 3600, "refresh_token": "rotated-refresh-token", "scope": "openid email", "id_token": "header.payload.signature"}"#)
+                .create();
+            let fut = RefreshFlow::refresh_token(
+                client.clone(),
+                app_secret.clone(),
+                refresh_token.clone(),
+            );
+            match rt.block_on(fut).unwrap() {
+                RefreshResult::Success(tok) => {
+                    assert_eq!(Some("rotated-refresh-token".to_string()), tok.refresh_token);
+                    assert_eq!(Some("openid email".to_string()), tok.scope);
+                    assert_eq!(Some("header.payload.signature".to_string()), tok.id_token);
+                }
+                rr => panic!(format!("unexpected RefreshResult {:?}", rr)),
+            }
+            _m.assert();
+        }
         // Refresh error.
         {
             let _m = mockito::mock("POST", "/token")
@@ -0,0 +1,174 @@
+use crate::types::{ApplicationSecret, JsonError, RequestError};
+
+use futures::prelude::*;
+use hyper::header;
+use url::form_urlencoded;
+
+/// Google's token revocation endpoint.
+pub const GOOGLE_REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Tells the authorization server which kind of token is to be revoked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenTypeHint {
+    AccessToken,
+    RefreshToken,
+}
+
+impl AsRef<str> for TokenTypeHint {
+    fn as_ref(&self) -> &'static str {
+        match *self {
+            TokenTypeHint::AccessToken => "access_token",
+            TokenTypeHint::RefreshToken => "refresh_token",
+        }
+    }
+}
+
+/// Implements [OAuth 2.0 Token Revocation](https://tools.ietf.org/html/rfc7009).
+///
+/// Revoking a refresh token usually invalidates all access tokens issued for the same grant, too.
+pub struct RevokeFlow;
+
+impl RevokeFlow {
+    /// Revoke `token` at the given revocation endpoint (for Google, that is `GOOGLE_REVOKE_URL`).
+    ///
+    /// # Arguments
+    /// * `revoke_url` - the provider's revocation endpoint.
+    /// * `client_secret` - the client credentials are sent along if `client_id` is not empty;
+    ///   confidential clients have to authenticate.
+    /// * `token` - the access or refresh token to revoke, as indicated by `hint`.
+    ///
+    /// A token that is already invalid is reported as success, as recommended by RFC 7009.
+    pub fn revoke_token<C: 'static + hyper::client::connect::Connect>(
+        client: hyper::Client<C>,
+        revoke_url: &str,
+        client_secret: &ApplicationSecret,
+        token: String,
+        hint: TokenTypeHint,
+    ) -> impl Future<Item = (), Error = RequestError> {
+        let mut params = vec![
+            ("token", token),
+            ("token_type_hint", hint.as_ref().to_string()),
+        ];
+        if !client_secret.client_id.is_empty() {
+            params.push(("client_id", client_secret.client_id.clone()));
+            params.push(("client_secret", client_secret.client_secret.clone()));
+        }
+        let req = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+
+        let request = hyper::Request::post(revoke_url)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(hyper::Body::from(req))
+            .into_future()
+            .map_err(|e| RequestError::BadServerResponse(format!("bad revocation URL: {}", e)));
+
+        request
+            .and_then(move |request| client.request(request).map_err(RequestError::ClientError))
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map_err(RequestError::ClientError)
+                    .map(move |body| (status, body))
+            })
+            .and_then(|(status, body)| {
+                if status.is_success() {
+                    return Ok(());
+                }
+                let body = String::from_utf8_lossy(&body);
+                match serde_json::from_str::<JsonError>(&body) {
+                    // The token is unknown or was revoked before.
+                    Ok(ref e) if e.error == "invalid_token" => Ok(()),
+                    Ok(e) => Err(RequestError::from(e)),
+                    Err(_) => Err(RequestError::BadServerResponse(format!(
+                        "token revocation failed with status {}: {}",
+                        status, body
+                    ))),
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper_rustls::HttpsConnector;
+
+    #[test]
+    fn test_revoke_end2end() {
+        let server_url = mockito::server_url();
+        let revoke_url = format!("{}/revoke", server_url);
+        let app_secret = ApplicationSecret {
+            client_id: "myclient".to_string(),
+            client_secret: "mysecret".to_string(),
+            ..Default::default()
+        };
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        // Success
+        {
+            let _m = mockito::mock("POST", "/revoke")
+                .match_body(mockito::Matcher::Regex(
+                    "^token=my-refresh-token&token_type_hint=refresh_token&client_id=myclient.*"
+                        .to_string(),
+                ))
+                .with_status(200)
+                .create();
+            let fut = RevokeFlow::revoke_token(
+                client.clone(),
+                &revoke_url,
+                &app_secret,
+                "my-refresh-token".to_string(),
+                TokenTypeHint::RefreshToken,
+            );
+            rt.block_on(fut).expect("revoke");
+            _m.assert();
+        }
+        // Already revoked.
+        {
+            let _m = mockito::mock("POST", "/revoke")
+                .with_status(400)
+                .with_body(r#"{"error": "invalid_token"}"#)
+                .create();
+            let fut = RevokeFlow::revoke_token(
+                client.clone(),
+                &revoke_url,
+                &app_secret,
+                "my-access-token".to_string(),
+                TokenTypeHint::AccessToken,
+            );
+            rt.block_on(fut).expect("revoke");
+            _m.assert();
+        }
+        // Error.
+        {
+            let _m = mockito::mock("POST", "/revoke")
+                .with_status(503)
+                .with_body(r#"{"error": "temporarily_unavailable"}"#)
+                .create();
+            let fut = RevokeFlow::revoke_token(
+                client,
+                &revoke_url,
+                &app_secret,
+                "my-access-token".to_string(),
+                TokenTypeHint::AccessToken,
+            );
+            let err = rt.block_on(fut).unwrap_err();
+            assert!(format!("{}", err).contains("temporarily_unavailable"));
+            _m.assert();
+        }
+    }
+}
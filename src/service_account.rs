@@ -15,8 +15,12 @@ use std::default::Default;
 use std::sync::{Arc, Mutex};
 
 use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::id_token::IdTokenCache;
+use crate::revoke::{RevokeFlow, TokenTypeHint, GOOGLE_REVOKE_URL};
 use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
-use crate::types::{ApplicationSecret, GetToken, JsonError, RequestError, StringError, Token};
+use crate::types::{
+    ApplicationSecret, GetIdToken, GetToken, JsonError, RequestError, StringError, Token,
+};
 
 use futures::stream::Stream;
 use futures::{future, prelude::*};
@@ -37,7 +41,7 @@ use hyper;
 use serde_json;
 
 const GRANT_TYPE: &'static str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
-const GOOGLE_RS256_HEAD: &'static str = "{\"alg\":\"RS256\",\"typ\":\"JWT\"}";
+const RS256_HEAD: &'static str = "{\"alg\":\"RS256\",\"typ\":\"JWT\"}";
 
 /// Encodes s as Base64
 fn encode_base64<T: AsRef<[u8]>>(s: T) -> String {
@@ -92,16 +96,21 @@ pub struct ServiceAccountKey {
 #[derive(Serialize, Debug)]
 struct Claims {
     iss: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     aud: String,
     exp: i64,
     iat: i64,
     sub: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     scope: String,
+    /// Requests an ID token for this audience instead of an access token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_audience: Option<String>,
 }
 
 /// A JSON Web Token ready for signing.
 struct JWT {
-    /// The value of GOOGLE_RS256_HEAD.
+    /// The value of RS256_HEAD.
     header: String,
     /// A Claims struct, expressing the set of desired permissions etc.
     claims: Claims,
@@ -111,13 +120,12 @@ impl JWT {
     /// Create a new JWT from claims.
     fn new(claims: Claims) -> JWT {
         JWT {
-            header: GOOGLE_RS256_HEAD.to_string(),
+            header: RS256_HEAD.to_string(),
             claims: claims,
         }
     }
 
     /// Set JWT header. Default is `{"alg":"RS256","typ":"JWT"}`.
-    #[allow(dead_code)]
     pub fn set_header(&mut self, head: String) {
         self.header = head;
     }
@@ -181,6 +189,7 @@ where
         iat: iat,
         sub: None,
         scope: scopes_string,
+        target_audience: None,
     }
 }
 
@@ -192,6 +201,8 @@ pub struct ServiceAccountAccess<C> {
     client: C,
     key: ServiceAccountKey,
     sub: Option<String>,
+    revoke_url: String,
+    self_signed_jwt: Option<SelfSignedJwt>,
 }
 
 impl ServiceAccountAccess<DefaultHyperClient> {
@@ -201,6 +212,8 @@ impl ServiceAccountAccess<DefaultHyperClient> {
             client: DefaultHyperClient,
             key,
             sub: None,
+            revoke_url: GOOGLE_REVOKE_URL.to_string(),
+            self_signed_jwt: None,
         }
     }
 }
@@ -219,6 +232,8 @@ where
             client: hyper_client,
             key: self.key,
             sub: self.sub,
+            revoke_url: self.revoke_url,
+            self_signed_jwt: self.self_signed_jwt,
         }
     }
 
@@ -230,18 +245,55 @@ where
         }
     }
 
-    /// Build the configured ServiceAccountAccess.
-    pub fn build(self) -> impl GetToken {
-        ServiceAccountAccessImpl::new(self.client.build_hyper_client(), self.key, self.sub)
+    /// Use the provided token revocation endpoint. The default is `GOOGLE_REVOKE_URL`.
+    pub fn revoke_url(self, url: String) -> Self {
+        ServiceAccountAccess {
+            revoke_url: url,
+            ..self
+        }
+    }
+
+    /// Instead of exchanging a signed JWT for an access token at the token endpoint, use the JWT
+    /// itself as access token. Google APIs accept such self-signed JWTs if their `aud` claim is
+    /// the API's endpoint, e.g. `https://pubsub.googleapis.com/`; if `audience` is `None`, the
+    /// JWT carries the requested scopes in a `scope` claim instead.
+    ///
+    /// Self-signed JWTs can not act on behalf of a user, so they are not used if a `sub` is set.
+    pub fn self_signed_jwt(self, audience: Option<String>) -> Self {
+        ServiceAccountAccess {
+            self_signed_jwt: Some(SelfSignedJwt { audience }),
+            ..self
+        }
+    }
+
+    /// Build the configured ServiceAccountAccess. Besides access tokens, it yields ID tokens for a
+    /// target audience (see `GetIdToken`).
+    pub fn build(self) -> impl GetToken + GetIdToken {
+        let mut access =
+            ServiceAccountAccessImpl::new(self.client.build_hyper_client(), self.key, self.sub);
+        access.revoke_url = self.revoke_url;
+        if access.sub.is_none() {
+            access.self_signed_jwt = self.self_signed_jwt;
+        }
+        access
     }
 }
 
+/// Configures minting self-signed JWTs, see `ServiceAccountAccess::self_signed_jwt()`.
+#[derive(Clone, Debug)]
+struct SelfSignedJwt {
+    audience: Option<String>,
+}
+
 #[derive(Clone)]
 struct ServiceAccountAccessImpl<C> {
     client: hyper::Client<C, hyper::Body>,
     key: ServiceAccountKey,
     cache: Arc<Mutex<MemoryStorage>>,
+    id_token_cache: IdTokenCache,
     sub: Option<String>,
+    revoke_url: String,
+    self_signed_jwt: Option<SelfSignedJwt>,
 }
 
 impl<C> ServiceAccountAccessImpl<C>
@@ -253,7 +305,10 @@ where
             client,
             key,
             cache: Arc::new(Mutex::new(MemoryStorage::default())),
+            id_token_cache: IdTokenCache::default(),
             sub,
+            revoke_url: GOOGLE_REVOKE_URL.to_string(),
+            self_signed_jwt: None,
         }
     }
 }
@@ -276,10 +331,18 @@ impl TokenResponse {
             refresh_token: Some(String::new()),
             expires_in: self.expires_in,
             expires_in_timestamp: Some(expires_ts),
+            scope: None,
+            id_token: None,
         }
     }
 }
 
+/// This is the schema of the server's response to an ID token request.
+#[derive(Deserialize, Debug)]
+struct IdTokenResponse {
+    id_token: String,
+}
+
 impl<'a, C: 'static + hyper::client::connect::Connect> ServiceAccountAccessImpl<C> {
     /// Send a request for a new Bearer token to the OAuth provider.
     fn request_token(
@@ -290,6 +353,81 @@ impl<'a, C: 'static + hyper::client::connect::Connect> ServiceAccountAccessImpl<
     ) -> impl Future<Item = Token, Error = RequestError> {
         let mut claims = init_claims_from_key(&key, &scopes);
         claims.sub = sub.clone();
+        Self::request(client, key, claims).then(|token: Result<TokenResponse, RequestError>| {
+            match token {
+                Err(e) => return Err(e),
+                Ok(token) => {
+                    if token.access_token.is_none()
+                        || token.token_type.is_none()
+                        || token.expires_in.is_none()
+                    {
+                        Err(RequestError::BadServerResponse(format!(
+                            "Token response lacks fields: {:?}",
+                            token
+                        )))
+                    } else {
+                        Ok(token.to_oauth_token())
+                    }
+                }
+            }
+        })
+    }
+
+    /// Mint a self-signed JWT and use it as access token, without contacting the OAuth provider.
+    fn self_signed_token(
+        key: &ServiceAccountKey,
+        config: &SelfSignedJwt,
+        scopes: &[String],
+    ) -> Result<Token, RequestError> {
+        let mut claims = init_claims_from_key(key, scopes);
+        // The service account asserts its own identity.
+        claims.sub = Some(claims.iss.clone());
+        match config.audience {
+            Some(ref audience) => {
+                claims.aud = audience.clone();
+                claims.scope = String::new();
+            }
+            None => claims.aud = String::new(),
+        }
+        let (iat, exp) = (claims.iat, claims.exp);
+        let mut jwt = JWT::new(claims);
+        if let Some(ref kid) = key.private_key_id {
+            jwt.set_header(format!(
+                "{{\"alg\":\"RS256\",\"typ\":\"JWT\",\"kid\":\"{}\"}}",
+                kid
+            ));
+        }
+        let signed = jwt
+            .sign(key.private_key.as_ref().unwrap())
+            .map_err(RequestError::LowLevelError)?;
+        Ok(Token {
+            access_token: signed,
+            token_type: "Bearer".to_string(),
+            refresh_token: None,
+            expires_in: Some(exp - iat),
+            expires_in_timestamp: Some(exp),
+            scope: None,
+            id_token: None,
+        })
+    }
+
+    /// Send a request for a new ID token for `target_audience` to the OAuth provider.
+    fn request_id_token(
+        client: hyper::client::Client<C>,
+        key: ServiceAccountKey,
+        target_audience: String,
+    ) -> impl Future<Item = String, Error = RequestError> {
+        let mut claims = init_claims_from_key(&key, &Vec::<String>::new());
+        claims.target_audience = Some(target_audience);
+        Self::request(client, key, claims).map(|response: IdTokenResponse| response.id_token)
+    }
+
+    /// Sign `claims` and exchange them for the token described by `R`.
+    fn request<R: 'static + serde::de::DeserializeOwned + Send>(
+        client: hyper::client::Client<C>,
+        key: ServiceAccountKey,
+        claims: Claims,
+    ) -> impl Future<Item = R, Error = RequestError> {
         let signed = JWT::new(claims)
             .sign(key.private_key.as_ref().unwrap())
             .into_future();
@@ -327,22 +465,33 @@ impl<'a, C: 'static + hyper::client::connect::Connect> ServiceAccountAccessImpl<
                     serde_json::from_str(&s).map_err(RequestError::JSONError)
                 }
             })
-            .then(|token: Result<TokenResponse, RequestError>| match token {
-                Err(e) => return Err(e),
-                Ok(token) => {
-                    if token.access_token.is_none()
-                        || token.token_type.is_none()
-                        || token.expires_in.is_none()
-                    {
-                        Err(RequestError::BadServerResponse(format!(
-                            "Token response lacks fields: {:?}",
-                            token
-                        )))
-                    } else {
-                        Ok(token.to_oauth_token())
-                    }
-                }
-            })
+    }
+}
+
+impl<C: 'static> GetIdToken for ServiceAccountAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn id_token<A>(
+        &mut self,
+        target_audience: A,
+    ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
+    where
+        A: Into<String>,
+    {
+        let target_audience = target_audience.into();
+        if let Some(token) = self.id_token_cache.get(&target_audience) {
+            return Box::new(future::ok(token));
+        }
+        let cache = self.id_token_cache.clone();
+        Box::new(
+            Self::request_id_token(
+                self.client.clone(),
+                self.key.clone(),
+                target_audience.clone(),
+            )
+            .and_then(move |token| cache.set(target_audience, token)),
+        )
     }
 }
 
@@ -380,13 +529,23 @@ where
         });
 
         let cache = self.cache.clone();
-        let req_token = Self::request_token(
-            self.client.clone(),
-            self.sub.clone(),
-            self.key.clone(),
-            scps0.iter().map(|s| s.to_string()).collect(),
-        )
-        .then(move |r| match r {
+        let scopes: Vec<String> = scps0.iter().map(|s| s.to_string()).collect();
+        let new_token: Box<dyn Future<Item = Token, Error = RequestError> + Send> =
+            match self.self_signed_jwt {
+                Some(ref config) => {
+                    let (key, config) = (self.key.clone(), config.clone());
+                    Box::new(futures::lazy(move || {
+                        Self::self_signed_token(&key, &config, &scopes)
+                    }))
+                }
+                None => Box::new(Self::request_token(
+                    self.client.clone(),
+                    self.sub.clone(),
+                    self.key.clone(),
+                    scopes,
+                )),
+            };
+        let req_token = new_token.then(move |r| match r {
             Ok(token) => {
                 let _ = cache.lock().unwrap().set(
                     hash,
@@ -416,13 +575,59 @@ where
     fn api_key(&mut self) -> Option<String> {
         None
     }
+
+    /// Revokes the cached access token for the given scopes and removes it from the cache.
+    fn revoke<I, T>(&mut self, scopes: I) -> Box<dyn Future<Item = (), Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scps) = hash_scopes(scopes);
+        let stored = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(hash, &scps.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+        let token = match stored {
+            Ok(Some(token)) => token,
+            // Nothing to revoke.
+            Ok(None) => return Box::new(future::ok(())),
+            Err(e) => return Box::new(future::err(RequestError::Cache(Box::new(e)))),
+        };
+        let cache = self.cache.clone();
+        let evict = move |()| {
+            cache
+                .lock()
+                .unwrap()
+                .set(
+                    hash,
+                    &scps.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+                    None,
+                )
+                .map_err(|e| RequestError::Cache(Box::new(e)))
+        };
+        if self.self_signed_jwt.is_some() {
+            // Self-signed JWTs are not known to the provider; they just expire.
+            return Box::new(future::result(evict(())));
+        }
+        Box::new(
+            RevokeFlow::revoke_token(
+                self.client.clone(),
+                &self.revoke_url,
+                &ApplicationSecret::default(),
+                token.access_token,
+                TokenTypeHint::AccessToken,
+            )
+            .and_then(evict),
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::helper::service_account_key_from_file;
-    use crate::types::GetToken;
+    use crate::types::{GetIdToken, GetToken};
 
     use hyper;
     use hyper_rustls::HttpsConnector;
@@ -508,6 +713,38 @@ mod tests {
 
             _m.assert();
         }
+        // Revocation: the token is only forgotten once the provider has revoked it.
+        {
+            let _m = mock("POST", "/token")
+                .with_status(200)
+                .with_header("content-type", "text/json")
+                .with_body(json_response)
+                .expect(1)
+                .create();
+            let mut acc = ServiceAccountAccessImpl::new(client.clone(), key.clone(), None);
+            acc.revoke_url = format!("{}/revoke", server_url);
+            let scopes = vec!["https://www.googleapis.com/auth/pubsub"];
+            rt.block_on(acc.token(scopes.clone())).expect("token");
+            let cached = |acc: &ServiceAccountAccessImpl<_>| {
+                let (hash, _) = hash_scopes(scopes.clone());
+                acc.cache.lock().unwrap().get(hash, &scopes).unwrap()
+            };
+
+            let _r = mock("POST", "/revoke")
+                .with_status(503)
+                .with_body("unavailable")
+                .expect(1)
+                .create();
+            assert!(rt.block_on(acc.revoke(scopes.clone())).is_err());
+            assert!(cached(&acc).is_some());
+            _r.assert();
+
+            let _r = mock("POST", "/revoke").with_status(200).expect(1).create();
+            rt.block_on(acc.revoke(scopes.clone())).expect("revoke");
+            assert!(cached(&acc).is_none());
+            _r.assert();
+            _m.assert();
+        }
         // Malformed response.
         {
             let _m = mock("POST", "/token")
@@ -527,9 +764,91 @@ mod tests {
             rt.block_on(fut).expect("block_on");
             _m.assert();
         }
+        // ID token.
+        {
+            let id_token = format!(
+                "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+                base64::encode_config(
+                    &format!(
+                        r#"{{"iss":"https://accounts.google.com","sub":"1234","aud":"https://service.run.app","exp":{},"iat":0}}"#,
+                        chrono::Utc::now().timestamp() + 3600
+                    ),
+                    base64::URL_SAFE_NO_PAD
+                )
+            );
+            let _m = mock("POST", "/token")
+                .match_body(mockito::Matcher::Regex(
+                    "^grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion=.*"
+                        .to_string(),
+                ))
+                .with_status(200)
+                .with_header("content-type", "text/json")
+                .with_body(format!(r#"{{"id_token": "{}"}}"#, id_token))
+                .expect(1)
+                .create();
+            let mut acc = ServiceAccountAccessImpl::new(client.clone(), key.clone(), None);
+            // The second request is served from the cache.
+            for _ in 0..2 {
+                let tok = rt
+                    .block_on(acc.id_token("https://service.run.app"))
+                    .expect("block_on");
+                assert_eq!(tok, id_token);
+            }
+            _m.assert();
+        }
         rt.shutdown_on_idle().wait().expect("shutdown");
     }
 
+    #[test]
+    fn test_self_signed_jwt() {
+        let key = service_account_key_from_file(TEST_PRIVATE_KEY_PATH).unwrap();
+        let decode = |tok: &Token| -> serde_json::Value {
+            let claims = tok.access_token.split('.').nth(1).unwrap();
+            serde_json::from_slice(&base64::decode_config(claims, base64::URL_SAFE).unwrap())
+                .unwrap()
+        };
+
+        // No token endpoint is involved, so a client is never used.
+        let mut acc = ServiceAccountAccess::new(key.clone())
+            .self_signed_jwt(Some("https://pubsub.googleapis.com/".to_string()))
+            .build();
+        let tok = acc
+            .token(vec!["https://www.googleapis.com/auth/pubsub"])
+            .wait()
+            .unwrap();
+        let claims = decode(&tok);
+        assert_eq!(claims["aud"], "https://pubsub.googleapis.com/");
+        assert_eq!(claims["iss"], claims["sub"]);
+        assert!(claims.get("scope").is_none());
+        assert!(!tok.expired());
+        // The token is cached.
+        let again = acc
+            .token(vec!["https://www.googleapis.com/auth/pubsub"])
+            .wait()
+            .unwrap();
+        assert_eq!(tok, again);
+
+        let mut acc = ServiceAccountAccess::new(key).self_signed_jwt(None).build();
+        let tok = acc
+            .token(vec!["https://www.googleapis.com/auth/pubsub"])
+            .wait()
+            .unwrap();
+        let claims = decode(&tok);
+        assert_eq!(claims["scope"], "https://www.googleapis.com/auth/pubsub");
+        assert!(claims.get("aud").is_none());
+    }
+
+    #[test]
+    fn test_jwt_target_audience_claims() {
+        let key = service_account_key_from_file(TEST_PRIVATE_KEY_PATH).unwrap();
+        let mut claims = super::init_claims_from_key(&key, &Vec::<String>::new());
+        claims.target_audience = Some("https://service.run.app".to_string());
+        let claims = serde_json::to_value(&claims).unwrap();
+
+        assert_eq!(claims["target_audience"], "https://service.run.app");
+        assert!(claims.get("scope").is_none());
+    }
+
     // Valid but deactivated key.
     const TEST_PRIVATE_KEY_PATH: &'static str = "examples/Sanguine-69411a0c0eea.json";
 
@@ -11,8 +11,12 @@ use std::fs;
 use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::{Read, Write};
+use std::process;
 
+use crate::encryption::{self, DecryptionError, EncryptionKeys};
 use crate::types::Token;
+use fs2::FileExt;
+use futures::{future, prelude::*};
 use itertools::Itertools;
 
 /// Implements a specialized storage to set and retrieve `Token` instances.
@@ -35,6 +39,55 @@ pub trait TokenStorage {
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error>;
 }
 
+/// An asynchronous variant of `TokenStorage`, for storage backends that have to perform
+/// (potentially slow) I/O, such as a network cache or a database. The returned futures are run
+/// by the `Authenticator` without blocking the executor.
+///
+/// Every `TokenStorage` is an `AsyncTokenStorage` as well, so only implement this trait if your
+/// storage is asynchronous.
+pub trait AsyncTokenStorage {
+    type Error: 'static + Error + Send + Sync;
+
+    /// If `token` is None, it is invalid or revoked and should be removed from storage.
+    /// Otherwise, it should be saved.
+    fn set_async(
+        &mut self,
+        scope_hash: u64,
+        scopes: &[&str],
+        token: Option<Token>,
+    ) -> Box<dyn Future<Item = (), Error = Self::Error> + Send>;
+    /// A `None` result indicates that there is no token for the given scope_hash.
+    fn get_async(
+        &self,
+        scope_hash: u64,
+        scopes: &[&str],
+    ) -> Box<dyn Future<Item = Option<Token>, Error = Self::Error> + Send>;
+}
+
+impl<S: TokenStorage> AsyncTokenStorage for S {
+    type Error = S::Error;
+
+    fn set_async(
+        &mut self,
+        scope_hash: u64,
+        scopes: &[&str],
+        token: Option<Token>,
+    ) -> Box<dyn Future<Item = (), Error = Self::Error> + Send> {
+        Box::new(future::result(self.set(
+            scope_hash,
+            &scopes.to_vec(),
+            token,
+        )))
+    }
+    fn get_async(
+        &self,
+        scope_hash: u64,
+        scopes: &[&str],
+    ) -> Box<dyn Future<Item = Option<Token>, Error = Self::Error> + Send> {
+        Box::new(future::result(self.get(scope_hash, &scopes.to_vec())))
+    }
+}
+
 /// Calculate a hash value describing the scopes, and return a sorted Vec of the scopes.
 pub fn hash_scopes<I, T>(scopes: I) -> (u64, Vec<String>)
 where
@@ -172,76 +225,163 @@ struct JSONTokens {
 }
 
 /// Serializes tokens to a JSON file on disk.
+///
+/// Several processes may share one file: Every modification takes an advisory lock on a
+/// `<location>.lock` file, merges the change into the current contents of the file, and
+/// atomically replaces it by writing to a temporary file first. On Unix, the files are only
+/// accessible by their owner.
 #[derive(Default)]
 pub struct DiskTokenStorage {
     location: String,
     tokens: Vec<JSONToken>,
+    encryption: Option<EncryptionKeys>,
 }
 
 impl DiskTokenStorage {
     pub fn new<S: AsRef<str>>(location: S) -> Result<DiskTokenStorage, io::Error> {
+        Self::open(location, None)
+    }
+
+    /// Like `new()`, but encrypts the file with the given keys (see `EncryptionKeys`), so that
+    /// refresh tokens are not stored in plain text. A plain text file, as written by `new()`, is
+    /// encrypted right away, so that enabling encryption keeps the stored tokens. If the file can
+    /// not be decrypted, an `io::Error` of kind `InvalidData` wrapping a `DecryptionError` is
+    /// returned.
+    pub fn new_encrypted<S: AsRef<str>>(
+        location: S,
+        keys: EncryptionKeys,
+    ) -> Result<DiskTokenStorage, io::Error> {
+        Self::open(location, Some(keys))
+    }
+
+    fn open<S: AsRef<str>>(
+        location: S,
+        encryption: Option<EncryptionKeys>,
+    ) -> Result<DiskTokenStorage, io::Error> {
         let mut dts = DiskTokenStorage {
             location: location.as_ref().to_owned(),
             tokens: Vec::new(),
+            encryption,
         };
 
-        // best-effort
-        let read_result = dts.load_from_file();
-
-        match read_result {
-            Result::Ok(()) => Result::Ok(dts),
-            Result::Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::NotFound => Result::Ok(dts), // File not found; ignore and create new one
-                    _ => Result::Err(e),                        // e.g. PermissionDenied
-                }
-            }
+        // Without a token file there is nothing to read, and the lock file is only created once
+        // tokens are written; the directory may not even exist yet.
+        match fs::metadata(&dts.location) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(dts),
+            _ => {}
+        }
+        let lock = dts.lock(false)?;
+        let (tokens, plaintext) = dts.read_file()?;
+        dts.tokens = tokens;
+        lock.unlock()?;
+        if plaintext {
+            // The file was written before encryption was enabled. Another process may have
+            // encrypted it in the meantime, so read it again with the lock held exclusively.
+            let lock = dts.lock(true)?;
+            dts.tokens = dts.read_tokens()?;
+            dts.write_tokens()?;
+            lock.unlock()?;
         }
+        Ok(dts)
     }
 
-    fn load_from_file(&mut self) -> Result<(), io::Error> {
-        let mut f = fs::OpenOptions::new().read(true).open(&self.location)?;
-        let mut contents = String::new();
-
-        match f.read_to_string(&mut contents) {
-            Result::Err(e) => return Result::Err(e),
-            Result::Ok(_sz) => (),
+    /// Opens (and creates, if necessary) the lock file and locks it. The lock is released when
+    /// the returned file is closed.
+    fn lock(&self, exclusive: bool) -> Result<fs::File, io::Error> {
+        let f = create_options()
+            .read(true)
+            .write(true)
+            .open(format!("{}.lock", self.location))?;
+        if exclusive {
+            f.lock_exclusive()?;
+        } else {
+            f.lock_shared()?;
         }
+        Ok(f)
+    }
 
-        let tokens: JSONTokens;
+    /// Reads the tokens currently stored on disk; a missing file contains no tokens.
+    fn read_tokens(&self) -> Result<Vec<JSONToken>, io::Error> {
+        self.read_file().map(|(tokens, _)| tokens)
+    }
 
-        match serde_json::from_str(&contents) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(t) => tokens = t,
+    /// Like `read_tokens()`, additionally telling whether the file is in plain text although
+    /// encryption is enabled.
+    fn read_file(&self) -> Result<(Vec<JSONToken>, bool), io::Error> {
+        let mut f = match fs::OpenOptions::new().read(true).open(&self.location) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+            Err(e) => return Err(e),
+        };
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        let mut plaintext = false;
+        if let Some(ref keys) = self.encryption {
+            match encryption::decrypt(keys, &contents) {
+                Ok(decrypted) => contents = decrypted,
+                Err(ref e) if is_not_encrypted(e) => plaintext = true,
+                Err(e) => return Err(e),
+            }
         }
 
-        for t in tokens.tokens {
-            self.tokens.push(t);
+        match serde_json::from_str::<JSONTokens>(&contents) {
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            Ok(t) => Ok((t.tokens, plaintext)),
         }
-        return Result::Ok(());
     }
 
-    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
-        let mut jsontokens = JSONTokens { tokens: Vec::new() };
+    /// Writes the tokens to a temporary file next to the destination and renames it, so that
+    /// readers never observe a partially written file. Must be called with the lock held.
+    fn write_tokens(&self) -> Result<(), io::Error> {
+        let jsontokens = JSONTokens {
+            tokens: self.tokens.clone(),
+        };
+        let mut serialized = serde_json::to_string(&jsontokens)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(ref keys) = self.encryption {
+            serialized = encryption::encrypt(keys, &serialized)?;
+        }
 
-        for token in self.tokens.iter() {
-            jsontokens.tokens.push((*token).clone());
+        let tmp_location = format!("{}.{}.tmp", self.location, process::id());
+        let result = create_options()
+            .write(true)
+            .truncate(true)
+            .open(&tmp_location)
+            .and_then(|mut f| {
+                f.write_all(serialized.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp_location, &self.location));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_location);
         }
+        result
+    }
 
-        let serialized;
+    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
+        let lock = self.lock(true)?;
+        self.write_tokens()?;
+        lock.unlock()
+    }
+}
 
-        match serde_json::to_string(&jsontokens) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(s) => serialized = s,
-        }
+/// Whether `e` tells that a file is not encrypted.
+fn is_not_encrypted(e: &io::Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<DecryptionError>())
+        .map_or(false, |e| *e == DecryptionError::NotEncrypted)
+}
 
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.location)?;
-        f.write(serialized.as_ref()).map(|_| ())
+/// Options for creating files only accessible by the current user.
+fn create_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+    options
 }
 
 impl TokenStorage for DiskTokenStorage {
@@ -252,6 +392,10 @@ impl TokenStorage for DiskTokenStorage {
         scopes: &Vec<&str>,
         token: Option<Token>,
     ) -> Result<(), Self::Error> {
+        // Other processes may have changed the file since we last read it.
+        let lock = self.lock(true)?;
+        self.tokens = self.read_tokens()?;
+
         let matched = self.tokens.iter().find_position(|x| x.hash == scope_hash);
         if let Some(_) = matched {
             self.tokens.retain(|x| x.hash != scope_hash);
@@ -268,7 +412,8 @@ impl TokenStorage for DiskTokenStorage {
                 ()
             }
         }
-        self.dump_to_file()
+        self.write_tokens()?;
+        lock.unlock()
     }
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
         let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
@@ -290,3 +435,158 @@ impl TokenStorage for DiskTokenStorage {
         Result::Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(access_token: &str) -> Token {
+        Token {
+            access_token: access_token.to_string(),
+            refresh_token: Some("refreshtoken".to_string()),
+            token_type: "Bearer".to_string(),
+            expires_in: None,
+            expires_in_timestamp: None,
+            scope: None,
+            id_token: None,
+        }
+    }
+
+    #[test]
+    fn test_disk_storage_shared_file() {
+        let dir = std::env::temp_dir().join(format!("yup-oauth2-storage-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let location = dir.join("tokens.json");
+        let location = location.to_str().unwrap();
+
+        // Two storages on the same file, as used by two processes.
+        let mut first = DiskTokenStorage::new(location).unwrap();
+        let mut second = DiskTokenStorage::new(location).unwrap();
+        first.set(1, &vec!["scope1"], Some(token("first"))).unwrap();
+        second
+            .set(2, &vec!["scope2"], Some(token("second")))
+            .unwrap();
+
+        // The second storage merged its token with the one written by the first.
+        let reloaded = DiskTokenStorage::new(location).unwrap();
+        assert_eq!(
+            reloaded
+                .get(1, &vec!["scope1"])
+                .unwrap()
+                .unwrap()
+                .access_token,
+            "first"
+        );
+        assert_eq!(
+            reloaded
+                .get(2, &vec!["scope2"])
+                .unwrap()
+                .unwrap()
+                .access_token,
+            "second"
+        );
+
+        first.set(2, &vec!["scope2"], None).unwrap();
+        let reloaded = DiskTokenStorage::new(location).unwrap();
+        assert!(reloaded.get(2, &vec!["scope2"]).unwrap().is_none());
+        assert!(reloaded.get(1, &vec!["scope1"]).unwrap().is_some());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(location).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // Only the token file and the lock file remain.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_storage_missing_directory() {
+        let dir = std::env::temp_dir().join(format!("yup-oauth2-missing-{}", process::id()));
+        let location = dir.join("tokens.json");
+        let location = location.to_str().unwrap();
+
+        let mut storage = DiskTokenStorage::new(location).unwrap();
+        assert!(storage.get(1, &vec!["scope1"]).unwrap().is_none());
+        assert!(!dir.exists());
+
+        // Writing fails until the directory is created.
+        assert!(storage.set(1, &vec!["scope1"], Some(token("t"))).is_err());
+        fs::create_dir_all(&dir).unwrap();
+        storage.set(1, &vec!["scope1"], Some(token("t"))).unwrap();
+        assert!(DiskTokenStorage::new(location)
+            .unwrap()
+            .get(1, &vec!["scope1"])
+            .unwrap()
+            .is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_disk_storage() {
+        let dir = std::env::temp_dir().join(format!("yup-oauth2-encrypted-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let location = dir.join("tokens.json");
+        let location = location.to_str().unwrap();
+
+        let keys = EncryptionKeys::new("key1", [7; 32]);
+        let mut storage = DiskTokenStorage::new_encrypted(location, keys.clone()).unwrap();
+        storage
+            .set(1, &vec!["scope1"], Some(token("secret")))
+            .unwrap();
+        let contents = fs::read_to_string(location).unwrap();
+        assert!(!contents.contains("secret") && !contents.contains("refreshtoken"));
+
+        let rotated = EncryptionKeys::new("key2", [8; 32]).old_key("key1", [7; 32]);
+        let storage = DiskTokenStorage::new_encrypted(location, rotated).unwrap();
+        assert_eq!(
+            storage
+                .get(1, &vec!["scope1"])
+                .unwrap()
+                .unwrap()
+                .access_token,
+            "secret"
+        );
+
+        let err = DiskTokenStorage::new_encrypted(location, EncryptionKeys::new("key1", [9; 32]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(format!("{}", err).contains("could not decrypt"));
+        // Plain storage refuses to read the encrypted file, too.
+        assert!(DiskTokenStorage::new(location).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_plaintext_disk_storage() {
+        let dir = std::env::temp_dir().join(format!("yup-oauth2-migrate-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let location = dir.join("tokens.json");
+        let location = location.to_str().unwrap();
+
+        let mut plain = DiskTokenStorage::new(location).unwrap();
+        plain
+            .set(1, &vec!["scope1"], Some(token("secret")))
+            .unwrap();
+
+        // Enabling encryption keeps the stored tokens, and encrypts the file.
+        let keys = EncryptionKeys::new("key1", [7; 32]);
+        let storage = DiskTokenStorage::new_encrypted(location, keys.clone()).unwrap();
+        assert_eq!(
+            storage
+                .get(1, &vec!["scope1"])
+                .unwrap()
+                .unwrap()
+                .access_token,
+            "secret"
+        );
+        let contents = fs::read_to_string(location).unwrap();
+        assert!(!contents.contains("secret") && !contents.contains("refreshtoken"));
+        let reopened = DiskTokenStorage::new_encrypted(location, keys).unwrap();
+        assert!(reopened.get(1, &vec!["scope1"]).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use crate::id_token::{self, IdTokenClaims};
+
 use chrono::{DateTime, TimeZone, Utc};
 use hyper;
 use std::error::Error;
@@ -5,7 +7,7 @@ use std::fmt;
 use std::io;
 use std::str::FromStr;
 
-use futures::prelude::*;
+use futures::{future, prelude::*};
 
 /// A marker trait for all Flows
 pub trait Flow {
@@ -253,6 +255,129 @@ pub trait GetToken {
     /// Return an application secret with at least token_uri, client_secret, and client_id filled
     /// in. This is used for refreshing tokens without interaction from the flow.
     fn application_secret(&self) -> ApplicationSecret;
+
+    /// Revoke the token obtained for the given scopes at the provider, and forget it.
+    ///
+    /// Token sources that don't keep tokens return an error.
+    fn revoke<I, T>(&mut self, scopes: I) -> Box<dyn Future<Item = (), Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let _ = scopes.into_iter();
+        Box::new(future::err(RequestError::UserError(
+            "this token source does not support revocation".to_string(),
+        )))
+    }
+}
+
+/// A provider for OpenID Connect ID tokens, yielding tokens asserting the caller's identity to
+/// the given audience, e.g. the URL of a Cloud Run service or the client ID of an IAP-protected
+/// backend. The returned JWT is sent as Bearer token.
+pub trait GetIdToken {
+    fn id_token<A>(
+        &mut self,
+        target_audience: A,
+    ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
+    where
+        A: Into<String>;
+}
+
+/// An object-safe variant of `GetToken`, implemented for every `GetToken`.
+trait DynGetToken: Send {
+    fn dyn_token(
+        &mut self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>;
+    fn dyn_api_key(&mut self) -> Option<String>;
+    fn dyn_application_secret(&self) -> ApplicationSecret;
+    fn dyn_revoke(
+        &mut self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = (), Error = RequestError> + Send>;
+}
+
+impl<G: GetToken + Send> DynGetToken for G {
+    fn dyn_token(
+        &mut self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.token(scopes)
+    }
+    fn dyn_api_key(&mut self) -> Option<String> {
+        self.api_key()
+    }
+    fn dyn_application_secret(&self) -> ApplicationSecret {
+        self.application_secret()
+    }
+    fn dyn_revoke(
+        &mut self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = (), Error = RequestError> + Send> {
+        self.revoke(scopes)
+    }
+}
+
+/// A boxed token source. It is used where the concrete `GetToken` implementation is only
+/// determined at runtime, e.g. by `ApplicationDefaultCredentials`.
+pub struct BoxedTokenSource {
+    source: Box<dyn DynGetToken>,
+    quota_project_id: Option<String>,
+}
+
+impl BoxedTokenSource {
+    /// Box the given token source.
+    pub fn new<G: 'static + GetToken + Send>(source: G) -> BoxedTokenSource {
+        BoxedTokenSource {
+            source: Box::new(source),
+            quota_project_id: None,
+        }
+    }
+
+    /// Set the quota project that requests made with our tokens are billed to.
+    pub fn with_quota_project_id(self, quota_project_id: Option<String>) -> BoxedTokenSource {
+        BoxedTokenSource {
+            quota_project_id,
+            ..self
+        }
+    }
+
+    /// The quota project named in the credentials, if any. Send it in the `x-goog-user-project`
+    /// header of API requests, so that they are billed to it.
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+}
+
+impl GetToken for BoxedTokenSource {
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.source
+            .dyn_token(scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        self.source.dyn_api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        self.source.dyn_application_secret()
+    }
+
+    fn revoke<I, T>(&mut self, scopes: I) -> Box<dyn Future<Item = (), Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.source
+            .dyn_revoke(scopes.into_iter().map(Into::into).collect())
+    }
 }
 
 /// Represents a token as returned by OAuth2 servers.
@@ -281,6 +406,10 @@ pub struct Token {
     /// timestamp is seconds since epoch indicating when the token will expire in absolute terms.
     /// use expiry_date() to convert to DateTime.
     pub expires_in_timestamp: Option<i64>,
+    /// The space-delimited scopes granted to the access_token, if the server listed them.
+    pub scope: Option<String>,
+    /// The OpenID Connect ID token, if the server returned one.
+    pub id_token: Option<String>,
 }
 
 impl Token {
@@ -306,6 +435,14 @@ impl Token {
         Utc.timestamp(expires_in_timestamp, 0).into()
     }
 
+    /// Returns the decoded claims of our OpenID Connect ID token, or `None` if the server did not
+    /// return one (usually because the `openid` scope was not requested).
+    ///
+    /// The token's signature is not verified.
+    pub fn id_token_claims(&self) -> Option<Result<IdTokenClaims, RequestError>> {
+        self.id_token.as_ref().map(|t| id_token::decode_claims(t))
+    }
+
     /// Adjust our stored expiry format to be absolute, using the current time.
     pub fn set_expiry_absolute(&mut self) -> &mut Token {
         if self.expires_in_timestamp.is_some() {
@@ -328,7 +465,7 @@ pub enum FlowType {
     /// [device authentication](https://developers.google.com/youtube/v3/guides/authentication#devices). Only works
     /// for certain scopes.
     /// Contains the device token URL; for google, that is
-    /// https://accounts.google.com/o/oauth2/device/code (exported as `GOOGLE_DEVICE_CODE_URL`)
+    /// https://oauth2.googleapis.com/device/code (exported as `GOOGLE_DEVICE_CODE_URL`)
     Device(String),
     /// [installed app flow](https://developers.google.com/identity/protocols/OAuth2InstalledApp). Required
     /// for Drive, Calendar, Gmail...; Requires user to paste a code from the browser.
@@ -0,0 +1,714 @@
+//! This module verifies signed JWTs, such as the ID tokens returned by the flows or tokens
+//! presented to your own services, against the keys of a JSON Web Key Set (JWKS).
+//!
+//! RS256 and ES256 signatures are supported. The keys are either supplied up front (see
+//! `JwtVerifier::jwks()` and `jwks_from_file()`) or fetched from the issuer's certificates URL,
+//! such as `GOOGLE_CERTS_URL`, whenever a token names a key ID that is not known yet.
+//!
+//! Resources:
+//! - [JSON Web Key](https://tools.ietf.org/html/rfc7517)
+//! - [OpenID Connect Core, ID Token Validation](https://openid.net/specs/openid-connect-core-1_0.html#IDTokenValidation)
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::authenticator::{duplicate_error, DefaultHyperClient, HyperClientBuilder};
+use crate::id_token::{self, IdTokenClaims};
+use crate::types::RequestError;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::Shared;
+use futures::{future, prelude::*};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+/// The JWKS of the keys Google signs ID tokens with.
+pub const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+/// The issuers of Google ID tokens.
+pub const GOOGLE_ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+
+/// A single public key of a JWKS.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Jwk {
+    /// The key type, `RSA` or `EC`.
+    pub kty: String,
+    /// The key ID, which is matched against the `kid` header of tokens.
+    pub kid: Option<String>,
+    /// The algorithm the key is used with, e.g. `RS256`.
+    pub alg: Option<String>,
+    /// The intended use of the key; `sig` for signature keys.
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    /// The RSA modulus, base64url encoded.
+    pub n: Option<String>,
+    /// The RSA public exponent, base64url encoded.
+    pub e: Option<String>,
+    /// The elliptic curve, e.g. `P-256`.
+    pub crv: Option<String>,
+    /// The x coordinate of an EC key, base64url encoded.
+    pub x: Option<String>,
+    /// The y coordinate of an EC key, base64url encoded.
+    pub y: Option<String>,
+}
+
+/// A JSON Web Key Set: `{"keys": [...]}`.
+///
+/// Use `jwks_from_file()` to read one from disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// The reasons a JWT is rejected by `JwtVerifier::verify()`.
+#[derive(Debug)]
+pub enum VerificationError {
+    /// The token is not a well-formed JWT, or its claims could not be decoded.
+    Malformed(String),
+    /// The token is signed with an algorithm other than RS256 and ES256.
+    UnsupportedAlgorithm(String),
+    /// No key with the given key ID is known, not even after fetching the certificates URL.
+    UnknownKey(Option<String>),
+    /// The signature does not match any suitable key.
+    InvalidSignature,
+    /// The token was issued by the given, unexpected issuer.
+    InvalidIssuer(String),
+    /// The token is intended for the given audiences only.
+    InvalidAudience(Vec<String>),
+    /// The token expired at the given date.
+    Expired(DateTime<Utc>),
+    /// The token must not be accepted before the given date.
+    NotYetValid(DateTime<Utc>),
+    /// The token's nonce does not match the expected nonce.
+    InvalidNonce,
+    /// The keys could not be fetched from the certificates URL.
+    KeyFetch(RequestError),
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerificationError::Malformed(ref s) => write!(f, "malformed JWT: {}", s),
+            VerificationError::UnsupportedAlgorithm(ref alg) => {
+                write!(f, "unsupported JWT algorithm '{}'", alg)
+            }
+            VerificationError::UnknownKey(Some(ref kid)) => write!(f, "unknown key ID '{}'", kid),
+            VerificationError::UnknownKey(None) => "no key to verify the JWT with".fmt(f),
+            VerificationError::InvalidSignature => "invalid JWT signature".fmt(f),
+            VerificationError::InvalidIssuer(ref iss) => write!(f, "unexpected issuer '{}'", iss),
+            VerificationError::InvalidAudience(ref aud) => {
+                write!(f, "unexpected audience '{}'", aud.join(" "))
+            }
+            VerificationError::Expired(ref date) => write!(f, "JWT expired at {}", date),
+            VerificationError::NotYetValid(ref date) => {
+                write!(f, "JWT is not valid before {}", date)
+            }
+            VerificationError::InvalidNonce => "unexpected JWT nonce".fmt(f),
+            VerificationError::KeyFetch(ref e) => write!(f, "could not fetch JWKS: {}", e),
+        }
+    }
+}
+
+impl Error for VerificationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            VerificationError::KeyFetch(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>, VerificationError> {
+    base64::decode_config(s.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| VerificationError::Malformed(format!("{}", e)))
+}
+
+/// Returns true if `key` can verify signatures made with `alg`.
+fn key_matches(key: &Jwk, alg: &str) -> bool {
+    if key.alg.as_deref().unwrap_or(alg) != alg || key.key_use.as_deref().unwrap_or("sig") != "sig"
+    {
+        return false;
+    }
+    match alg {
+        "RS256" => key.kty == "RSA",
+        "ES256" => key.kty == "EC" && key.crv.as_deref().unwrap_or("P-256") == "P-256",
+        _ => false,
+    }
+}
+
+/// Verify `signature` of `message` with `key`.
+fn verify_signature(key: &Jwk, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+    let component = |c: &Option<String>| c.as_ref().and_then(|c| decode_base64(c).ok());
+    match alg {
+        "RS256" => match (component(&key.n), component(&key.e)) {
+            (Some(n), Some(e)) => {
+                let strip = |b: &[u8]| b.iter().position(|&b| b != 0).unwrap_or(0);
+                RsaPublicKeyComponents {
+                    n: &n[strip(&n)..],
+                    e: &e[strip(&e)..],
+                }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok()
+            }
+            _ => false,
+        },
+        "ES256" => match (component(&key.x), component(&key.y)) {
+            (Some(ref x), Some(ref y)) if x.len() == 32 && y.len() == 32 => {
+                // An uncompressed SEC1 point.
+                let mut point = vec![4];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Fetch a JWKS from `url`.
+fn fetch_jwks<C: 'static + hyper::client::connect::Connect>(
+    client: hyper::Client<C>,
+    url: String,
+) -> impl Future<Item = Jwks, Error = RequestError> {
+    hyper::Request::get(url)
+        .body(hyper::Body::empty())
+        .into_future()
+        .map_err(|e| RequestError::BadServerResponse(format!("bad certificates URL: {}", e)))
+        .and_then(move |request| client.request(request).map_err(RequestError::ClientError))
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map_err(RequestError::ClientError)
+                .map(move |body| (status, body))
+        })
+        .and_then(|(status, body)| {
+            if !status.is_success() {
+                return Err(RequestError::BadServerResponse(format!(
+                    "fetching JWKS failed with status {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+            serde_json::from_slice(&body).map_err(RequestError::JSONError)
+        })
+}
+
+/// A fetch of the certificates URL, shared by all verifications waiting for it.
+type KeyFetch = Shared<Box<dyn Future<Item = (), Error = RequestError> + Send>>;
+
+/// The keys fetched from the certificates URL, shared by all clones of a verifier.
+#[derive(Default)]
+struct FetchedKeys {
+    keys: Vec<Jwk>,
+    /// When the last fetch completed, successfully or not.
+    fetched_at: Option<Instant>,
+    in_progress: Option<KeyFetch>,
+}
+
+/// Verifies RS256 and ES256 signed JWTs, and validates their claims.
+///
+/// Keys fetched from the certificates URL are cached and shared by all clones of the verifier;
+/// the URL is fetched again whenever a token names an unknown key ID, as happens after the issuer
+/// rotated its keys. Concurrent verifications wait for the same fetch, and the URL is fetched at
+/// most once per `min_refetch_interval()`.
+///
+/// ```test_harness,no_run
+/// use futures::prelude::*;
+/// use yup_oauth2::{JwtVerifier, GOOGLE_CERTS_URL, GOOGLE_ISSUERS};
+///
+/// fn main() {
+///     let verifier = JwtVerifier::new("my-client-id.apps.googleusercontent.com")
+///         .certs_url(GOOGLE_CERTS_URL)
+///         .issuers(GOOGLE_ISSUERS.iter().cloned());
+///     let fut = verifier
+///         .verify("eyJhbGciOiJSUzI1NiIs...", None)
+///         .map(|claims| println!("logged in as {}", claims.sub))
+///         .map_err(|e| println!("error: {}", e));
+///     tokio::run(fut);
+/// }
+/// ```
+pub struct JwtVerifier<C> {
+    client: hyper::Client<C>,
+    keys: Vec<Jwk>,
+    fetched_keys: Arc<Mutex<FetchedKeys>>,
+    certs_url: Option<String>,
+    min_refetch_interval: Duration,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: chrono::Duration,
+}
+
+// Not derived, as that would require `C: Clone`.
+impl<C> Clone for JwtVerifier<C> {
+    fn clone(&self) -> Self {
+        JwtVerifier {
+            client: self.client.clone(),
+            keys: self.keys.clone(),
+            fetched_keys: self.fetched_keys.clone(),
+            certs_url: self.certs_url.clone(),
+            min_refetch_interval: self.min_refetch_interval,
+            issuers: self.issuers.clone(),
+            audiences: self.audiences.clone(),
+            leeway: self.leeway,
+        }
+    }
+}
+
+impl JwtVerifier<<DefaultHyperClient as HyperClientBuilder>::Connector> {
+    /// Create a verifier accepting tokens intended for `audience` (for ID tokens, that is the
+    /// client ID). It knows no keys yet; add them using `jwks()` or `certs_url()`.
+    pub fn new<S: Into<String>>(audience: S) -> Self {
+        JwtVerifier {
+            client: DefaultHyperClient.build_hyper_client(),
+            keys: Vec::new(),
+            fetched_keys: Arc::new(Mutex::new(FetchedKeys::default())),
+            certs_url: None,
+            min_refetch_interval: Duration::from_secs(60),
+            issuers: Vec::new(),
+            audiences: vec![audience.into()],
+            leeway: chrono::Duration::seconds(60),
+        }
+    }
+}
+
+impl<C> JwtVerifier<C>
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    /// Use the provided hyper client for fetching keys.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> JwtVerifier<NewC::Connector> {
+        JwtVerifier {
+            client: hyper_client.build_hyper_client(),
+            keys: self.keys,
+            fetched_keys: self.fetched_keys,
+            certs_url: self.certs_url,
+            min_refetch_interval: self.min_refetch_interval,
+            issuers: self.issuers,
+            audiences: self.audiences,
+            leeway: self.leeway,
+        }
+    }
+
+    /// Trust the keys of the given key set.
+    pub fn jwks(mut self, jwks: Jwks) -> Self {
+        self.keys.extend(jwks.keys);
+        self
+    }
+
+    /// Fetch the keys from the given URL (for Google, that is `GOOGLE_CERTS_URL`) if a token's
+    /// key is not known.
+    pub fn certs_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.certs_url = Some(url.into());
+        self
+    }
+
+    /// The minimum time between two fetches of the certificates URL (default: 60 seconds). Within
+    /// this interval, tokens naming an unknown key are rejected with `UnknownKey` right away.
+    pub fn min_refetch_interval(mut self, interval: Duration) -> Self {
+        self.min_refetch_interval = interval;
+        self
+    }
+
+    /// Additionally accept tokens intended for `audience`.
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+
+    /// Only accept tokens issued by one of the given issuers (for Google, that is
+    /// `GOOGLE_ISSUERS`). By default, any issuer is accepted.
+    pub fn issuers<I, S>(mut self, issuers: I) -> Self
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = S>,
+    {
+        self.issuers.extend(issuers.into_iter().map(Into::into));
+        self
+    }
+
+    /// The clock skew tolerated when checking `exp` and `nbf` (default: 60 seconds).
+    pub fn leeway(mut self, leeway: chrono::Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Verify the signature of `token` and validate its claims. If `nonce` is given, the token
+    /// must carry it in its `nonce` claim.
+    pub fn verify(
+        &self,
+        token: &str,
+        nonce: Option<&str>,
+    ) -> Box<dyn Future<Item = IdTokenClaims, Error = VerificationError> + Send> {
+        let (header, _) = match id_token::decode_segments(token) {
+            Ok(segments) => segments,
+            Err(e) => return Box::new(future::err(VerificationError::Malformed(e.to_string()))),
+        };
+        let header: Header = match serde_json::from_slice(&header) {
+            Ok(header) => header,
+            Err(e) => return Box::new(future::err(VerificationError::Malformed(e.to_string()))),
+        };
+        if header.alg != "RS256" && header.alg != "ES256" {
+            return Box::new(future::err(VerificationError::UnsupportedAlgorithm(
+                header.alg,
+            )));
+        }
+
+        let verifier = self.clone();
+        let token = token.to_string();
+        let nonce = nonce.map(str::to_string);
+        let candidates = self.candidate_keys(&header);
+        let keys: Box<dyn Future<Item = Vec<Jwk>, Error = VerificationError> + Send> =
+            match self.certs_url {
+                Some(ref url) if candidates.is_empty() => {
+                    let verifier = self.clone();
+                    let header_kid = header.kid.clone();
+                    let header_alg = header.alg.clone();
+                    Box::new(self.fetch_keys(url.clone()).map(move |()| {
+                        verifier.candidate_keys(&Header {
+                            alg: header_alg,
+                            kid: header_kid,
+                        })
+                    }))
+                }
+                _ => Box::new(future::ok(candidates)),
+            };
+        Box::new(keys.and_then(move |keys| {
+            if keys.is_empty() {
+                return Err(VerificationError::UnknownKey(header.kid));
+            }
+            let (message, sig) = token.split_at(token.rfind('.').unwrap());
+            let sig = decode_base64(&sig[1..])?;
+            if !keys
+                .iter()
+                .any(|key| verify_signature(key, &header.alg, message.as_bytes(), &sig))
+            {
+                return Err(VerificationError::InvalidSignature);
+            }
+            let claims = id_token::decode_claims(&token)
+                .map_err(|e| VerificationError::Malformed(e.to_string()))?;
+            verifier.validate(&claims, nonce.as_deref())?;
+            Ok(claims)
+        }))
+    }
+
+    /// Fetches the keys from `url`, unless they were fetched within the minimum refetch interval.
+    /// If a fetch is in progress already, waits for it instead.
+    fn fetch_keys(
+        &self,
+        url: String,
+    ) -> Box<dyn Future<Item = (), Error = VerificationError> + Send> {
+        let mut fetched = self.fetched_keys.lock().unwrap();
+        let fetch = match fetched.in_progress {
+            Some(ref fetch) => fetch.clone(),
+            None if fetched
+                .fetched_at
+                .map_or(false, |t| t.elapsed() < self.min_refetch_interval) =>
+            {
+                return Box::new(future::ok(()));
+            }
+            None => {
+                let fetched_keys = self.fetched_keys.clone();
+                let fetch: Box<dyn Future<Item = (), Error = RequestError> + Send> =
+                    Box::new(fetch_jwks(self.client.clone(), url).then(move |r| {
+                        let mut fetched = fetched_keys.lock().unwrap();
+                        fetched.in_progress = None;
+                        fetched.fetched_at = Some(Instant::now());
+                        fetched.keys = r?.keys;
+                        Ok(())
+                    }));
+                let fetch = fetch.shared();
+                fetched.in_progress = Some(fetch.clone());
+                fetch
+            }
+        };
+        Box::new(
+            fetch
+                .map(|_| ())
+                .map_err(|e| VerificationError::KeyFetch(duplicate_error(&e))),
+        )
+    }
+
+    /// The known keys suitable for verifying a token with the given header.
+    fn candidate_keys(&self, header: &Header) -> Vec<Jwk> {
+        let fetched = self.fetched_keys.lock().unwrap();
+        self.keys
+            .iter()
+            .chain(fetched.keys.iter())
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .filter(|key| key_matches(key, &header.alg))
+            .cloned()
+            .collect()
+    }
+
+    fn validate(
+        &self,
+        claims: &IdTokenClaims,
+        nonce: Option<&str>,
+    ) -> Result<(), VerificationError> {
+        if !self.issuers.is_empty() && !self.issuers.contains(&claims.iss) {
+            return Err(VerificationError::InvalidIssuer(claims.iss.clone()));
+        }
+        if !claims.aud.iter().any(|aud| self.audiences.contains(aud)) {
+            return Err(VerificationError::InvalidAudience(claims.aud.clone()));
+        }
+        let now = Utc::now();
+        let invalid_exp = || VerificationError::Malformed(format!("invalid exp {}", claims.exp));
+        let expiry = claims.expiry_date().map_err(|_| invalid_exp())?;
+        let valid_until = expiry
+            .checked_add_signed(self.leeway)
+            .ok_or_else(invalid_exp)?;
+        if valid_until <= now {
+            return Err(VerificationError::Expired(expiry));
+        }
+        if let Some(nbf) = claims.nbf {
+            let invalid_nbf = || VerificationError::Malformed(format!("invalid nbf {}", nbf));
+            let not_before = Utc.timestamp_opt(nbf, 0).single().ok_or_else(invalid_nbf)?;
+            let valid_from = not_before
+                .checked_sub_signed(self.leeway)
+                .ok_or_else(invalid_nbf)?;
+            if valid_from > now {
+                return Err(VerificationError::NotYetValid(not_before));
+            }
+        }
+        if let Some(nonce) = nonce {
+            if claims.nonce.as_deref() != Some(nonce) {
+                return Err(VerificationError::InvalidNonce);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::service_account_key_from_file;
+
+    use hyper_rustls::HttpsConnector;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, RsaKeyPair};
+    use rustls::internal::pemfile;
+
+    fn encode(b: &[u8]) -> String {
+        base64::encode_config(b, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn claims(aud: &str, exp_offset: i64) -> String {
+        let now = Utc::now().timestamp();
+        format!(
+            r#"{{"iss": "https://issuer", "sub": "1234", "aud": "{}", "exp": {}, "iat": {}, "nonce": "n0nce"}}"#,
+            aud,
+            now + exp_offset,
+            now
+        )
+    }
+
+    fn es256_key(kid: &str) -> (EcdsaKeyPair, Jwk) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .unwrap();
+        let pair =
+            EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+                .unwrap();
+        let point = pair.public_key().as_ref();
+        let jwk = Jwk {
+            kty: "EC".to_string(),
+            kid: Some(kid.to_string()),
+            alg: Some("ES256".to_string()),
+            key_use: Some("sig".to_string()),
+            n: None,
+            e: None,
+            crv: Some("P-256".to_string()),
+            x: Some(encode(&point[1..33])),
+            y: Some(encode(&point[33..65])),
+        };
+        (pair, jwk)
+    }
+
+    fn es256_token(pair: &EcdsaKeyPair, kid: &str, claims: &str) -> String {
+        let message = format!(
+            "{}.{}",
+            encode(format!(r#"{{"alg":"ES256","kid":"{}"}}"#, kid).as_bytes()),
+            encode(claims.as_bytes())
+        );
+        let sig = pair.sign(&SystemRandom::new(), message.as_bytes()).unwrap();
+        format!("{}.{}", message, encode(sig.as_ref()))
+    }
+
+    #[test]
+    fn test_verify_es256() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (pair, jwk) = es256_key("key1");
+        let verifier = JwtVerifier::new("client")
+            .issuers(vec!["https://issuer"])
+            .jwks(Jwks { keys: vec![jwk] });
+
+        let token = es256_token(&pair, "key1", &claims("client", 3600));
+        let verified = rt.block_on(verifier.verify(&token, Some("n0nce"))).unwrap();
+        assert_eq!(verified.sub, "1234");
+
+        match rt.block_on(verifier.verify(&token, Some("other"))) {
+            Err(VerificationError::InvalidNonce) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = es256_token(&pair, "key1", &claims("other-client", 3600));
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::InvalidAudience(ref aud)) => assert_eq!(aud, &["other-client"]),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = es256_token(&pair, "key1", &claims("client", -3600));
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::Expired(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = es256_token(&pair, "key2", &claims("client", 3600));
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::UnknownKey(Some(ref kid))) => assert_eq!(kid, "key2"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Signed by another key using the same key ID.
+        let (other_pair, _) = es256_key("key1");
+        let token = es256_token(&other_pair, "key1", &claims("client", 3600));
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::InvalidSignature) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // The latest and earliest representable times, which the leeway must not overflow.
+        let token = es256_token(
+            &pair,
+            "key1",
+            r#"{"iss": "https://issuer", "sub": "1234", "aud": "client", "exp": 8210266876799, "iat": 0}"#,
+        );
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::Malformed(ref e)) => assert_eq!(e, "invalid exp 8210266876799"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = es256_token(
+            &pair,
+            "key1",
+            &claims("client", 3600).replace(r#""iat""#, r#""nbf": -8334601228800, "iat""#),
+        );
+        match rt.block_on(verifier.verify(&token, None)) {
+            Err(VerificationError::Malformed(ref e)) => assert_eq!(e, "invalid nbf -8334601228800"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match rt.block_on(verifier.verify("no.jwt", None)) {
+            Err(VerificationError::Malformed(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_verify_rs256_fetched_key() {
+        let key = service_account_key_from_file("examples/Sanguine-69411a0c0eea.json").unwrap();
+        let pem = key.private_key.unwrap().replace("\\n", "\n");
+        let der = pemfile::pkcs8_private_keys(&mut pem.as_bytes()).unwrap()[0].clone();
+        let pair = RsaKeyPair::from_pkcs8(&der.0).unwrap();
+        let jwks = Jwks {
+            keys: vec![Jwk {
+                kty: "RSA".to_string(),
+                kid: Some("rsakey".to_string()),
+                alg: Some("RS256".to_string()),
+                key_use: Some("sig".to_string()),
+                n: Some(encode(
+                    pair.public_key()
+                        .modulus()
+                        .big_endian_without_leading_zero(),
+                )),
+                e: Some(encode(
+                    pair.public_key()
+                        .exponent()
+                        .big_endian_without_leading_zero(),
+                )),
+                crv: None,
+                x: None,
+                y: None,
+            }],
+        };
+
+        let message = format!(
+            "{}.{}",
+            encode(br#"{"alg":"RS256","kid":"rsakey","typ":"JWT"}"#),
+            encode(claims("client", 3600).as_bytes())
+        );
+        let mut sig = vec![0; pair.public_modulus_len()];
+        pair.sign(
+            &signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut sig,
+        )
+        .unwrap();
+        let token = format!("{}.{}", message, encode(&sig));
+
+        let _m = mockito::mock("GET", "/verifier/certs")
+            .with_status(200)
+            .with_body(serde_json::to_string(&jwks).unwrap())
+            .expect(1)
+            .create();
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let verifier = JwtVerifier::new("client")
+            .hyper_client(client)
+            .certs_url(format!("{}/verifier/certs", mockito::server_url()));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        // The second verification uses the cached key.
+        for _ in 0..2 {
+            let verified = rt.block_on(verifier.verify(&token, None)).unwrap();
+            assert_eq!(verified.iss, "https://issuer");
+        }
+        _m.assert();
+    }
+
+    #[test]
+    fn test_unknown_keys_fetched_once() {
+        let (pair, jwk) = es256_key("key1");
+        let _m = mockito::mock("GET", "/verifier/rotated-certs")
+            .with_status(200)
+            .with_body(serde_json::to_string(&Jwks { keys: vec![jwk] }).unwrap())
+            .expect(1)
+            .create();
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let verifier = JwtVerifier::new("client")
+            .hyper_client(client)
+            .certs_url(format!("{}/verifier/rotated-certs", mockito::server_url()));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Concurrent verifications wait for the same fetch, and unknown keys are not fetched
+        // again within the minimum refetch interval.
+        let unknown = |kid: &str| {
+            verifier
+                .verify(&es256_token(&pair, kid, &claims("client", 3600)), None)
+                .then(Ok::<_, ()>)
+        };
+        let (first, second) = rt.block_on(unknown("key2").join(unknown("key3"))).unwrap();
+        let third = rt.block_on(unknown("key4")).unwrap();
+        for r in [first, second, third].iter() {
+            match r {
+                Err(VerificationError::UnknownKey(Some(_))) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        let token = es256_token(&pair, "key1", &claims("client", 3600));
+        assert!(rt.block_on(verifier.verify(&token, None)).is_ok());
+        _m.assert();
+    }
+}